@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use identity::NodeId;
+
+/// Turns user input like `build` or `#build` into a channel name, or `None` if it can't be one.
+/// Names may not contain whitespace or commas, since they're listed comma-separated in the TXT
+/// record.
+pub fn normalize(name: &str) -> Option<String> {
+    let name = name.trim_start_matches('#');
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c == ',') {
+        return None;
+    }
+    Some(format!("#{}", name.to_lowercase()))
+}
+
+/// Encodes channel names for the `ch` TXT record entry.
+pub fn to_txt(channels: &BTreeSet<String>) -> String {
+    channels.iter().cloned().collect::<Vec<_>>().join(",")
+}
+
+/// Decodes the `ch` TXT record entry, skipping anything that isn't a channel name.
+pub fn from_txt(txt: &str) -> BTreeSet<String> {
+    txt.split(',').filter_map(normalize).collect()
+}
+
+/// Which channels we're in, and which channels each of our peers is in.
+#[derive(Debug, Default)]
+pub struct Channels {
+    joined: BTreeSet<String>,
+    peers: HashMap<NodeId, BTreeSet<String>>,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Channels::default()
+    }
+
+    /// Returns `false` if we were already in the channel.
+    pub fn join(&mut self, channel: &str) -> bool {
+        self.joined.insert(channel.to_owned())
+    }
+
+    /// Returns `false` if we weren't in the channel.
+    pub fn leave(&mut self, channel: &str) -> bool {
+        self.joined.remove(channel)
+    }
+
+    pub fn is_joined(&self, channel: &str) -> bool {
+        self.joined.contains(channel)
+    }
+
+    pub fn joined(&self) -> &BTreeSet<String> {
+        &self.joined
+    }
+
+    /// Replaces everything we know about a peer's channels, e.g. with its TXT record.
+    pub fn set_peer_channels(&mut self, node_id: NodeId, channels: BTreeSet<String>) {
+        self.peers.insert(node_id, channels);
+    }
+
+    pub fn peer_joined(&mut self, node_id: NodeId, channel: String) {
+        self.peers.entry(node_id).or_default().insert(channel);
+    }
+
+    pub fn peer_left(&mut self, node_id: &NodeId, channel: &str) {
+        if let Some(channels) = self.peers.get_mut(node_id) {
+            channels.remove(channel);
+        }
+    }
+
+    pub fn drop_peer(&mut self, node_id: &NodeId) {
+        self.peers.remove(node_id);
+    }
+
+    /// Whether the peer `node_id` is in `channel`, as far as we know.
+    pub fn is_member(&self, node_id: &NodeId, channel: &str) -> bool {
+        self.peers
+            .get(node_id)
+            .is_some_and(|channels| channels.contains(channel))
+    }
+
+    /// The peers in `channel`, to route messages for it to.
+    pub fn members<'a>(&'a self, channel: &'a str) -> impl Iterator<Item = &'a NodeId> + 'a {
+        self.peers
+            .iter()
+            .filter(move |(_, channels)| channels.contains(channel))
+            .map(|(node_id, _)| node_id)
+    }
+
+    /// Every channel anyone we know of is in, with the number of peers in it (not counting us).
+    pub fn list(&self) -> BTreeMap<&str, usize> {
+        let mut list: BTreeMap<&str, usize> = self
+            .joined
+            .iter()
+            .map(|channel| (channel.as_str(), 0))
+            .collect();
+        for channel in self.peers.values().flat_map(|channels| channels.iter()) {
+            *list.entry(channel.as_str()).or_insert(0) += 1;
+        }
+        list
+    }
+}
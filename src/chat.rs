@@ -1,23 +1,67 @@
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{BufMut, BytesMut};
 use futures::sync::mpsc;
-use std::net::SocketAddr;
+use std::collections::BTreeSet;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio;
 use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::timer::Delay;
 
-struct Lines {
-    socket: TcpStream,
+use identity::{Identity, NodeId, PublicKey};
+use known_peers::{KnownPeers, OnKeyChange, Trust};
+use message::{Frame, Message};
+use peer::Peer;
+use presence::Presence;
+use ratelimit::{RateLimit, RateLimiter, Violation};
+use reconnect::{Backoff, ConnectionState};
+use secure::{self, Hello, Keys, SecureStream};
+use serde_json;
+use transfer::{Chunk, Expected, Signal};
+
+/// The most a length-prefixed record may hold, so that a peer can't make us buffer without end.
+pub const MAX_RECORD_LEN: usize = 1024 * 1024;
+
+/// Marks a binary record.
+const BINARY: u8 = 0;
+/// Marks a length-prefixed line.
+const LINE: u8 = 1;
+
+/// How lines are delimited on a connection, as agreed in the handshake.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Framing {
+    /// Each line ends with `\r\n`, so lines can't contain one.
+    Crlf,
+    /// Each line is sent like a binary record, but marked with a one byte rather than a zero
+    /// byte, so lines may contain anything.
+    LengthPrefixed,
+}
+
+/// What `Lines` reads off the socket.
+enum Record {
+    /// A line of text, without its framing
+    Line(BytesMut),
+    /// A binary record: a zero byte, then the payload's length as a big-endian u32, then the
+    /// payload. No line of JSON starts with a zero byte, so the two can't be confused.
+    Binary(BytesMut),
+}
+
+struct Lines<S> {
+    socket: S,
     rd: BytesMut,
     wr: BytesMut,
+    framing: Framing,
 }
 
-impl Lines {
-    fn new(socket: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite> Lines<S> {
+    fn new(socket: S, framing: Framing) -> Self {
         Lines {
             socket,
             rd: BytesMut::new(),
             wr: BytesMut::new(),
+            framing,
         }
     }
 
@@ -34,9 +78,48 @@ impl Lines {
         }
     }
 
-    /// Adds a line of data to the write buffer.
+    /// Adds a line of data to the write buffer, framed however the connection agreed to.
     fn buffer(&mut self, line: &[u8]) {
-        self.wr.put(line);
+        match self.framing {
+            Framing::Crlf => {
+                self.wr.reserve(line.len() + 2);
+                self.wr.put(line);
+                self.wr.put(&b"\r\n"[..]);
+            }
+            Framing::LengthPrefixed => self.buffer_prefixed(LINE, line),
+        }
+    }
+
+    /// Adds a binary record to the write buffer.
+    fn buffer_binary(&mut self, payload: &[u8]) {
+        self.buffer_prefixed(BINARY, payload);
+    }
+
+    fn buffer_prefixed(&mut self, kind: u8, payload: &[u8]) {
+        self.wr.reserve(5 + payload.len());
+        self.wr.put_u8(kind);
+        self.wr.put_u32_be(payload.len() as u32);
+        self.wr.put(payload);
+    }
+
+    /// Chops a complete length-prefixed record off the front of the read buffer, if there is
+    /// one, and returns its payload.
+    fn take_prefixed(&mut self) -> Result<Option<BytesMut>, io::Error> {
+        if self.rd.len() < 5 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.rd[1], self.rd[2], self.rd[3], self.rd[4]]) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record too long",
+            ));
+        }
+        if self.rd.len() < 5 + len {
+            return Ok(None);
+        }
+        self.rd.split_to(5);
+        Ok(Some(self.rd.split_to(len)))
     }
 
     /// Resolves when the current write buffers has been fully written to the socket
@@ -51,18 +134,38 @@ impl Lines {
             // discard the first `n` bytes of the buffer
             self.wr.split_to(n);
         }
-        Ok(Async::Ready(()))
+        self.socket.poll_flush()
     }
 }
 
-impl Stream for Lines {
-    type Item = BytesMut;
+impl<S: AsyncRead + AsyncWrite> Stream for Lines<S> {
+    type Item = Record;
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
         // self.fill_read_buf will complete when the socket closes
         let sock_closed = self.fill_read_buf()?.is_ready();
 
+        let kind = match (self.rd.first(), self.framing) {
+            (Some(&BINARY), _) => Some(BINARY),
+            (Some(&LINE), Framing::LengthPrefixed) => Some(LINE),
+            (Some(_), Framing::LengthPrefixed) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown record type",
+                ))
+            }
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            return match self.take_prefixed()? {
+                Some(payload) if kind == BINARY => Ok(Async::Ready(Some(Record::Binary(payload)))),
+                Some(payload) => Ok(Async::Ready(Some(Record::Line(payload)))),
+                None if sock_closed => Ok(Async::Ready(None)),
+                None => Ok(Async::NotReady),
+            };
+        }
+
         // messages are delimited by \r\n, and `pos` is the index at which the message ends
         let pos = self.rd.windows(2).position(|bytes| bytes == b"\r\n");
 
@@ -73,7 +176,7 @@ impl Stream for Lines {
             // drop \r\n
             line.split_off(pos);
             // return the line
-            return Ok(Async::Ready(Some(line)));
+            return Ok(Async::Ready(Some(Record::Line(line))));
         }
 
         if sock_closed {
@@ -84,31 +187,810 @@ impl Stream for Lines {
     }
 }
 
-fn process(socket: TcpStream, tx: mpsc::UnboundedSender<(SocketAddr, Bytes)>) {
+/// A message handed to the application.
+#[derive(Debug)]
+pub struct Received {
+    /// The connection the message arrived on
+    pub addr: SocketAddr,
+    /// The peer at the other end of that connection, which isn't the author if the message
+    /// was relayed
+    pub peer: NodeId,
+    pub message: Message,
+    /// Whether the message carries a valid signature from the node it claims to be from
+    pub verified: bool,
+}
+
+/// Something a peer told us.
+#[derive(Debug)]
+pub enum Event {
+    Message(Received),
+    /// The peer at the other end of a connection joined a channel.
+    Join {
+        peer: NodeId,
+        channel: String,
+    },
+    /// The peer at the other end of a connection left a channel.
+    Leave {
+        peer: NodeId,
+        channel: String,
+    },
+    /// The peer at the other end of a connection told us its nickname, either in the handshake
+    /// or because it changed.
+    Nick {
+        peer: NodeId,
+        nick: String,
+    },
+    /// The peer at the other end of a connection changed its presence.
+    Presence {
+        peer: NodeId,
+        presence: Presence,
+    },
+    /// The peer at the other end of a connection stopped answering pings, whether or not
+    /// discovery has noticed it's gone.
+    Dropped {
+        peer: NodeId,
+    },
+    /// The peer at the other end of a connection said something about a file transfer.
+    File {
+        peer: NodeId,
+        signal: Signal,
+    },
+    /// The peer at the other end of a connection acknowledged a message we sent it.
+    Ack {
+        peer: NodeId,
+        id: String,
+    },
+    /// The peer at the other end of a connection dropped something we sent it because we were
+    /// sending too fast.
+    RateLimited {
+        peer: NodeId,
+    },
+    /// The peer at the other end of a connection asked for history. The answer goes to `reply`.
+    HistoryRequest {
+        peer: NodeId,
+        channel: Option<String>,
+        since: u64,
+        after: Option<String>,
+        reply: Sender,
+    },
+    /// The peer at the other end of a connection sent us history we asked for.
+    History {
+        peer: NodeId,
+        messages: Vec<Received>,
+    },
+    /// Our outbound connection to a peer changed state.
+    Connection {
+        peer: NodeId,
+        state: ConnectionState,
+    },
+    /// A peer's service name is pinned to a different identity than the one it presented.
+    KeyChanged {
+        servicename: String,
+        pinned: NodeId,
+        actual: NodeId,
+        /// Whether the connection was refused because of it
+        refused: bool,
+    },
+    /// Something went wrong with a connection that the user may want to know about.
+    Warning(String),
+}
+
+/// How often to check that a peer is still there.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Heartbeat {
+    /// How long to wait between pings
+    pub interval: Duration,
+    /// How long the peer may go without sending anything before it's considered dead
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Sends frames to a single peer.
+pub type Sender = mpsc::UnboundedSender<Frame>;
+
+/// Runs a connection, resolving once it has closed. Dropping it closes the connection.
+pub type Closed = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// Reads frames from a single peer and forwards them, as far as its rate limit admits the
+/// messages among them, and writes any frames queued for the peer.
+struct Connection {
+    lines: Lines<SecureStream<TcpStream>>,
+    addr: SocketAddr,
+    /// The verified node id of the peer
+    peer: NodeId,
+    tx: mpsc::UnboundedSender<Event>,
+    /// Frames to send to the peer. `None` once every sender has been dropped.
+    outgoing: Option<mpsc::UnboundedReceiver<Frame>>,
+    /// Queues frames in `outgoing`, for answering requests from the peer
+    reply: Sender,
+    limit: RateLimit,
+    limiter: RateLimiter,
+    /// The files we're waiting on, the only ones the peer may send us data for
+    expected: Expected,
+    /// A message held back by `Violation::Delay`, with its length on the wire, released when
+    /// the delay elapses.
+    held: Option<(Frame, usize, Delay)>,
+    heartbeat: Heartbeat,
+    /// Fires when it's time for the next ping
+    next_ping: Delay,
+    /// When the peer last sent us anything
+    last_heard: Instant,
+}
+
+impl Connection {
+    fn new(
+        stream: SecureStream<TcpStream>,
+        addr: SocketAddr,
+        tx: mpsc::UnboundedSender<Event>,
+        limit: RateLimit,
+        heartbeat: Heartbeat,
+        expected: Expected,
+    ) -> Self {
+        let peer = stream.remote_identity().node_id();
+        // We always can, so it's down to the peer
+        let framing = if stream.remote_hello().length_prefixed {
+            Framing::LengthPrefixed
+        } else {
+            Framing::Crlf
+        };
+        let (reply, outgoing) = mpsc::unbounded();
+        let _ = tx.unbounded_send(Event::Nick {
+            peer: peer.clone(),
+            nick: stream.remote_hello().nickname.clone(),
+        });
+        Connection {
+            peer,
+            lines: Lines::new(stream, framing),
+            addr,
+            tx,
+            outgoing: Some(outgoing),
+            reply,
+            limiter: RateLimiter::new(&limit),
+            limit,
+            expected,
+            held: None,
+            next_ping: Delay::new(Instant::now() + heartbeat.interval),
+            heartbeat,
+            last_heard: Instant::now(),
+        }
+    }
+
+    /// A sender for frames to the peer, good for as long as the connection is open.
+    fn sender(&self) -> Sender {
+        self.reply.clone()
+    }
+
+    fn buffer_frame(&mut self, frame: &Frame) {
+        if let Frame::FileChunk(ref chunk) = *frame {
+            self.lines.buffer_binary(&chunk.to_bytes());
+            return;
+        }
+        // Serializing a `Frame` can't fail: it's all strings and integers
+        let line = serde_json::to_vec(frame).unwrap();
+        self.lines.buffer(&line);
+    }
+
+    /// Moves every frame queued for the peer into the write buffer.
+    fn buffer_outgoing(&mut self) {
+        while let Some(mut outgoing) = self.outgoing.take() {
+            match outgoing.poll() {
+                Ok(Async::Ready(Some(frame))) => {
+                    self.outgoing = Some(outgoing);
+                    self.buffer_frame(&frame);
+                }
+                Ok(Async::NotReady) => {
+                    self.outgoing = Some(outgoing);
+                    return;
+                }
+                // All senders are gone, but the peer may still have something to say
+                Ok(Async::Ready(None)) | Err(()) => return,
+            }
+        }
+    }
+
+    /// Pings the peer whenever the interval elapses. Returns `false` if the peer has been quiet
+    /// for longer than the timeout.
+    fn poll_heartbeat(&mut self) -> Result<bool, io::Error> {
+        while self.next_ping.poll().map_err(io::Error::other)?.is_ready() {
+            if self.last_heard.elapsed() >= self.heartbeat.timeout {
+                return Ok(false);
+            }
+            self.buffer_frame(&Frame::Ping);
+            self.next_ping
+                .reset(Instant::now() + self.heartbeat.interval);
+        }
+        Ok(true)
+    }
+
+    /// Applies the rate limit to `frame`, which was `len` bytes on the wire. Only chat messages
+    /// count against it: control frames are small, and dropping them would break the
+    /// connection rather than slow down a chatty peer. Returns `false` if the connection should
+    /// be closed.
+    fn admit(&mut self, frame: Frame, len: usize) -> bool {
+        if !matches!(frame, Frame::Message(_)) {
+            self.receive(frame);
+            return true;
+        }
+        let wait = match self.limiter.check(len) {
+            Ok(()) => {
+                self.receive(frame);
+                return true;
+            }
+            Err(wait) => wait,
+        };
+        match self.limit.on_violation {
+            Violation::Drop => true,
+            Violation::Delay => {
+                self.held = Some((frame, len, Delay::new(Instant::now() + wait)));
+                true
+            }
+            Violation::Warn => {
+                self.buffer_frame(&Frame::RateLimited);
+                true
+            }
+            Violation::Disconnect => {
+                self.warn(format!("Disconnecting {}: rate limit exceeded", self.addr));
+                false
+            }
+        }
+    }
+
+    fn warn(&self, text: String) {
+        let _ = self.tx.unbounded_send(Event::Warning(text));
+    }
+
+    fn file(&self, signal: Signal) -> Event {
+        Event::File {
+            peer: self.peer.clone(),
+            signal,
+        }
+    }
+
+    /// Parses a binary record, which can only be part of a file, and hands it to the
+    /// application if it's part of a file we accepted.
+    fn receive_binary(&mut self, payload: &[u8]) {
+        match Chunk::parse(payload) {
+            Some(chunk) => {
+                let key = (self.peer.clone(), chunk.id.clone());
+                if !self.expected.lock().unwrap().contains(&key) {
+                    return;
+                }
+                let _ = self.tx.unbounded_send(Event::File {
+                    peer: self.peer.clone(),
+                    signal: Signal::Chunk(chunk),
+                });
+            }
+            None => self.warn(format!("Unreadable binary record from {}", self.addr)),
+        }
+    }
+
+    /// Parses a line into a frame. Returns `None`, and tells the application, if it's
+    /// unreadable.
+    fn parse(&self, line: &[u8]) -> Option<Frame> {
+        match serde_json::from_slice(line) {
+            Ok(frame) => Some(frame),
+            Err(_) => {
+                self.warn(format!(
+                    "Unreadable line from {}: {:?}",
+                    self.addr,
+                    String::from_utf8_lossy(line)
+                ));
+                None
+            }
+        }
+    }
+
+    /// Hands a frame to the application, verifying it if it's a message.
+    fn receive(&mut self, frame: Frame) {
+        let event = match frame {
+            Frame::Ping => {
+                self.buffer_frame(&Frame::Pong);
+                return;
+            }
+            // Hearing from the peer at all is what counts, and `poll` has already noted it
+            Frame::Pong => return,
+            Frame::Message(message) => {
+                let verified = message.verify();
+                let id = message.id.clone();
+                let received = Event::Message(Received {
+                    addr: self.addr,
+                    peer: self.peer.clone(),
+                    message,
+                    verified,
+                });
+                // Only acknowledge what the application actually got
+                if self.tx.unbounded_send(received).is_ok() {
+                    self.buffer_frame(&Frame::Ack { id });
+                }
+                return;
+            }
+            Frame::HistoryRequest {
+                channel,
+                since,
+                after,
+            } => Event::HistoryRequest {
+                peer: self.peer.clone(),
+                channel,
+                since,
+                after,
+                reply: self.sender(),
+            },
+            Frame::History { messages } => Event::History {
+                peer: self.peer.clone(),
+                messages: messages
+                    .into_iter()
+                    .map(|message| Received {
+                        addr: self.addr,
+                        peer: self.peer.clone(),
+                        verified: message.verify(),
+                        message,
+                    })
+                    .collect(),
+            },
+            Frame::FileOffer(offer) => self.file(Signal::Offer(offer)),
+            Frame::FileAccept { id, offset } => self.file(Signal::Accept { id, offset }),
+            Frame::FileReject { id } => self.file(Signal::Reject { id }),
+            Frame::FileProgress { id, received } => self.file(Signal::Progress { id, received }),
+            Frame::FileDone { id, ok } => self.file(Signal::Done { id, ok }),
+            // Chunks only ever arrive as binary records
+            Frame::FileChunk(_) => return,
+            Frame::Ack { id } => Event::Ack {
+                peer: self.peer.clone(),
+                id,
+            },
+            Frame::RateLimited => Event::RateLimited {
+                peer: self.peer.clone(),
+            },
+            Frame::Join { channel } => Event::Join {
+                peer: self.peer.clone(),
+                channel,
+            },
+            Frame::Leave { channel } => Event::Leave {
+                peer: self.peer.clone(),
+                channel,
+            },
+            Frame::Nick { nick } => Event::Nick {
+                peer: self.peer.clone(),
+                nick,
+            },
+            Frame::Presence(presence) => Event::Presence {
+                peer: self.peer.clone(),
+                presence,
+            },
+        };
+        let _ = self.tx.unbounded_send(event);
+    }
+}
+
+impl Future for Connection {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            self.buffer_outgoing();
+            if !self.poll_heartbeat()? {
+                let _ = self.tx.unbounded_send(Event::Dropped {
+                    peer: self.peer.clone(),
+                });
+                return Ok(Async::Ready(()));
+            }
+            // A slow reader on the other end shouldn't stop us from reading
+            self.lines.poll_flush()?;
+
+            if let Some((frame, len, mut delay)) = self.held.take() {
+                let elapsed = delay.poll().map_err(io::Error::other)?.is_ready();
+                if !elapsed {
+                    // Don't read any further until the held message is released
+                    self.held = Some((frame, len, delay));
+                    return Ok(Async::NotReady);
+                }
+                if !self.admit(frame, len) {
+                    return Ok(Async::Ready(()));
+                }
+                continue;
+            }
+
+            match try_ready!(self.lines.poll()) {
+                Some(Record::Line(line)) => {
+                    self.last_heard = Instant::now();
+                    let frame = match self.parse(&line) {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    if !self.admit(frame, line.len()) {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                // File data isn't held to the rate limit: the user asked for it, and anything
+                // else is dropped
+                Some(Record::Binary(payload)) => {
+                    self.last_heard = Instant::now();
+                    self.receive_binary(&payload);
+                }
+                None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+/// What connections need to know about the local node.
+#[derive(Clone)]
+pub struct Node {
+    pub keys: Keys,
+    pub identity: Identity,
+    /// Shared so that changes are picked up by new connections
+    pub nickname: Arc<Mutex<String>>,
+    /// The name we're advertised under, once we're registered. Shared for the same reason.
+    pub servicename: Arc<Mutex<Option<String>>>,
+    pub known_peers: Arc<Mutex<KnownPeers>>,
+    pub on_key_change: OnKeyChange,
+    pub limit: RateLimit,
+    pub heartbeat: Heartbeat,
+    pub backoff: Backoff,
+    /// Shared with the application, which knows what it's accepted
+    pub expected_files: Expected,
+    /// The port we accept connections on
+    pub port: u16,
+}
+
+impl Node {
+    pub fn nickname(&self) -> String {
+        self.nickname.lock().unwrap().clone()
+    }
+
+    fn hello(&self) -> Hello {
+        let servicename = self.servicename.lock().unwrap().clone();
+        Hello::new(&self.keys, &self.identity, self.nickname(), servicename)
+    }
+}
+
+fn process(socket: TcpStream, node: &Node, tx: mpsc::UnboundedSender<Event>) {
     let addr = socket.peer_addr().unwrap();
-    let lines = Lines::new(socket);
-    let connection = lines
-        .for_each(move |line| {
-            tx.unbounded_send((addr, Bytes::from(line)));
-            Ok(())
+    let limit = node.limit.clone();
+    let heartbeat = node.heartbeat.clone();
+    let expected = node.expected_files.clone();
+    let warnings = tx.clone();
+    let checks = tx.clone();
+    let node = node.clone();
+    let connection = secure::respond(socket, &node.keys, &node.hello())
+        .and_then(move |stream| {
+            // The peer may not be registered yet, and then there's nothing to check it against
+            if let Some(ref servicename) = stream.remote_hello().servicename {
+                check_known_peer(&node, servicename, &stream.remote_identity(), &checks)?;
+            }
+            Ok(stream)
+        })
+        .map_err(move |err| {
+            let warning = match err {
+                // The application has already been told
+                secure::Error::KeyChanged { .. } => return,
+                err => format!("Handshake with {} failed: {:?}", addr, err),
+            };
+            let _ = warnings.unbounded_send(Event::Warning(warning));
         })
-        .map_err(|_| ());
+        .and_then(move |stream| {
+            Connection::new(stream, addr, tx, limit, heartbeat, expected).map_err(|_| ())
+        });
     tokio::spawn(connection);
 }
 
+/// Listens on the node's port on every address, over IPv6 and IPv4 both.
+fn bind_everywhere(port: u16) -> io::Result<Vec<TcpListener>> {
+    let v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+    match TcpListener::bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)) {
+        // `[::]` usually takes IPv4 connections too, leaving nothing for `0.0.0.0`, but not where
+        // IPv6 sockets are made IPv6-only
+        Ok(v6) => match TcpListener::bind(&v4) {
+            Ok(v4) => Ok(vec![v6, v4]),
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => Ok(vec![v6]),
+            Err(err) => Err(err),
+        },
+        // Perhaps there's no IPv6 here
+        Err(err) => TcpListener::bind(&v4).map(|v4| vec![v4]).map_err(|_| err),
+    }
+}
+
+/// Listens on each of `addrs`, or on every address if there are none, which fails straight away
+/// if the port is taken, and returns a future that accepts connections.
 pub fn server(
-    tx: mpsc::UnboundedSender<(SocketAddr, Bytes)>,
-) -> impl Future<Item = (), Error = ()> {
-    let addr = "0.0.0.0:1337".parse().unwrap();
-    TcpListener::bind(&addr)
-        .unwrap()
-        .incoming()
-        .for_each(move |socket: TcpStream| {
-            process(socket, tx.clone());
+    node: Node,
+    tx: mpsc::UnboundedSender<Event>,
+    addrs: &[SocketAddr],
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listeners = if addrs.is_empty() {
+        bind_everywhere(node.port)?
+    } else {
+        addrs
+            .iter()
+            .map(TcpListener::bind)
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    let warnings = tx.clone();
+    let accepting = listeners.into_iter().map(move |listener| {
+        let node = node.clone();
+        let tx = tx.clone();
+        listener.incoming().for_each(move |socket: TcpStream| {
+            process(socket, &node, tx.clone());
             Ok(())
         })
-        .map_err(|e| {
-            println!("Error occurred in server: {:?}", e);
-            ()
+    });
+    Ok(future::join_all(accepting).map(|_| ()).map_err(move |e| {
+        let warning = format!("Error occurred in server: {:?}", e);
+        let _ = warnings.unbounded_send(Event::Warning(warning));
+    }))
+}
+
+/// Shakes hands with whoever is listening at `addr` to find out who it is, for peers that are
+/// configured rather than discovered. The peer goes by `name` in place of a service name.
+///
+/// Only the node id is kept: the transport key is made afresh each run, so once the peer
+/// restarts its fingerprint would never match again.
+pub fn probe(
+    addr: SocketAddr,
+    name: String,
+    node: &Node,
+) -> impl Future<Item = Peer, Error = secure::Error> {
+    let node = node.clone();
+    TcpStream::connect(&addr)
+        .from_err()
+        .and_then(move |socket| secure::initiate(socket, &node.keys, &node.hello()))
+        .map(move |stream| Peer {
+            servicename: name,
+            interface: 0,
+            hostname: addr.ip().to_string(),
+            socket_addr: addr,
+            fingerprint: None,
+            node_id: Some(stream.remote_identity().node_id()),
+            channels: BTreeSet::new(),
+            presence: Presence::default(),
+        })
+}
+
+/// Checks the verified identity of `peer` against the key pinned for its service name, telling
+/// the application if it changed.
+fn check_known_peer(
+    node: &Node,
+    servicename: &str,
+    key: &PublicKey,
+    tx: &mpsc::UnboundedSender<Event>,
+) -> Result<(), secure::Error> {
+    let trust = node.known_peers.lock().unwrap().check(servicename, key)?;
+    if let Trust::Changed { pinned } = trust {
+        let refused = node.on_key_change == OnKeyChange::Refuse;
+        let _ = tx.unbounded_send(Event::KeyChanged {
+            servicename: servicename.to_owned(),
+            pinned: pinned.node_id(),
+            actual: key.node_id(),
+            refused,
+        });
+        if refused {
+            return Err(secure::Error::KeyChanged {
+                servicename: servicename.to_owned(),
+                pinned: pinned.node_id(),
+                actual: key.node_id(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Opens an encrypted connection to `peer`, refusing it unless the peer's identity matches the
+/// node id it advertised, and its transport key the fingerprint, if it advertised one. Resolves
+/// to a sender for frames to the peer and the connection itself, which has to be run for
+/// anything to be sent or received; anything the peer sends back is forwarded to `tx`.
+pub fn connect(
+    peer: Peer,
+    node: &Node,
+    tx: mpsc::UnboundedSender<Event>,
+) -> impl Future<Item = (Sender, Closed), Error = secure::Error> {
+    let addr = peer.socket_addr;
+    let node = node.clone();
+    TcpStream::connect(&addr)
+        .from_err()
+        .and_then({
+            let node = node.clone();
+            move |socket| secure::initiate(socket, &node.keys, &node.hello())
+        })
+        .and_then(move |stream| {
+            if let Some(ref expected) = peer.fingerprint {
+                let actual = stream.remote_fingerprint();
+                if *expected != actual {
+                    return Err(secure::Error::FingerprintMismatch {
+                        expected: peer.fingerprint.clone(),
+                        actual,
+                    });
+                }
+            }
+            let actual = stream.remote_identity().node_id();
+            if peer.node_id.as_ref() != Some(&actual) {
+                return Err(secure::Error::IdentityMismatch {
+                    expected: peer.node_id,
+                    actual,
+                });
+            }
+            check_known_peer(&node, &peer.servicename, &stream.remote_identity(), &tx)?;
+            let connection = Connection::new(
+                stream,
+                addr,
+                tx,
+                node.limit,
+                node.heartbeat,
+                node.expected_files,
+            );
+            let sender = connection.sender();
+            let closed: Closed = Box::new(connection.then(|_| Ok(())));
+            Ok((sender, closed))
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+
+    /// A socket that hands out reads as they're queued, and would block once they run out.
+    #[derive(Default)]
+    struct MockSocket {
+        reads: VecDeque<Vec<u8>>,
+        closed: bool,
+        written: Vec<u8>,
+    }
+
+    impl Read for MockSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(mut data) => {
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    if n < data.len() {
+                        self.reads.push_front(data.split_off(n));
+                    }
+                    Ok(n)
+                }
+                None if self.closed => Ok(0),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for MockSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockSocket {}
+
+    impl AsyncWrite for MockSocket {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn lines(framing: Framing) -> Lines<MockSocket> {
+        Lines::new(MockSocket::default(), framing)
+    }
+
+    fn feed(lines: &mut Lines<MockSocket>, data: &[u8]) {
+        lines.socket.reads.push_back(data.to_vec());
+    }
+
+    /// Polls for the next record, expecting there to be one.
+    fn next(lines: &mut Lines<MockSocket>) -> Record {
+        match lines.poll().unwrap() {
+            Async::Ready(Some(record)) => record,
+            Async::Ready(None) => panic!("connection closed"),
+            Async::NotReady => panic!("no record yet"),
+        }
+    }
+
+    fn line(record: Record) -> Vec<u8> {
+        match record {
+            Record::Line(line) => line.to_vec(),
+            Record::Binary(_) => panic!("expected a line"),
+        }
+    }
+
+    fn prefixed(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn crlf_lines_are_split_on_crlf() {
+        let mut lines = lines(Framing::Crlf);
+        feed(&mut lines, b"one\r\ntwo\r\nthr");
+        assert_eq!(line(next(&mut lines)), b"one");
+        assert_eq!(line(next(&mut lines)), b"two");
+        assert!(lines.poll().unwrap().is_not_ready());
+        feed(&mut lines, b"ee\r\n");
+        assert_eq!(line(next(&mut lines)), b"three");
+    }
+
+    #[test]
+    fn a_record_split_across_reads_waits_for_the_rest() {
+        let mut lines = lines(Framing::LengthPrefixed);
+        let record = prefixed(LINE, b"line with\r\nin it");
+        // Split inside the length as well as inside the payload
+        feed(&mut lines, &record[..3]);
+        assert!(lines.poll().unwrap().is_not_ready());
+        feed(&mut lines, &record[3..9]);
+        assert!(lines.poll().unwrap().is_not_ready());
+        feed(&mut lines, &record[9..]);
+        assert_eq!(line(next(&mut lines)), b"line with\r\nin it");
+    }
+
+    #[test]
+    fn binary_records_arrive_whatever_the_framing() {
+        for &framing in &[Framing::Crlf, Framing::LengthPrefixed] {
+            let mut lines = lines(framing);
+            feed(&mut lines, &prefixed(BINARY, b"\r\n\0data"));
+            match next(&mut lines) {
+                Record::Binary(payload) => assert_eq!(&payload[..], b"\r\n\0data"),
+                Record::Line(_) => panic!("expected a binary record"),
+            }
+        }
+    }
+
+    #[test]
+    fn an_oversized_length_is_refused_before_it_arrives() {
+        let mut lines = lines(Framing::LengthPrefixed);
+        let mut header = vec![LINE];
+        header.extend_from_slice(&(MAX_RECORD_LEN as u32 + 1).to_be_bytes());
+        feed(&mut lines, &header);
+        let err = lines.poll().err().expect("expected an error");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn an_unknown_record_type_is_an_error() {
+        let mut lines = lines(Framing::LengthPrefixed);
+        feed(&mut lines, b"{\"not\": \"prefixed\"}");
+        assert!(lines.poll().is_err());
+    }
+
+    #[test]
+    fn a_partial_record_at_close_is_the_end() {
+        let mut lines = lines(Framing::LengthPrefixed);
+        feed(&mut lines, &prefixed(LINE, b"cut short")[..7]);
+        lines.socket.closed = true;
+        assert!(matches!(lines.poll().unwrap(), Async::Ready(None)));
+    }
+
+    #[test]
+    fn what_is_written_reads_back_the_same() {
+        for &framing in &[Framing::Crlf, Framing::LengthPrefixed] {
+            let mut writer = lines(framing);
+            writer.buffer(b"first");
+            writer.buffer_binary(b"\x00\x01\x02");
+            writer.buffer(b"second");
+            assert!(writer.poll_flush().unwrap().is_ready());
+
+            let mut reader = lines(framing);
+            feed(&mut reader, &writer.socket.written);
+            assert_eq!(line(next(&mut reader)), b"first");
+            match next(&mut reader) {
+                Record::Binary(payload) => assert_eq!(&payload[..], b"\x00\x01\x02"),
+                Record::Line(_) => panic!("expected a binary record"),
+            }
+            assert_eq!(line(next(&mut reader)), b"second");
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use dnssd::ServiceType;
+use known_peers::OnKeyChange;
+use ratelimit::Violation;
+
+/// The port we listen on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 1337;
+
+/// The DNS-SD service type localchat nodes advertise and browse for.
+pub const DEFAULT_SERVICE_TYPE: &str = "_localchat._tcp";
+
+/// How long `localchat peers` browses for by default.
+pub const DEFAULT_PEERS_WAIT: Duration = Duration::from_secs(3);
+
+pub const USAGE: &str = "\
+Usage: localchat [options] [command]
+
+Commands:
+  chat                     Chat interactively (the default)
+  listen                   Print incoming messages to stdout
+  peers [--wait <secs>]    Discover peers for a few seconds, list them and exit
+  send <peer> <message>    Send a private message, wait for it to arrive and exit
+  history [count]          Show the last messages from the history
+  known-peers              List the pinned identities
+  trust <service name>     Forget a peer's pinned identity
+  config                   Print the configuration in effect
+  help                     Show this
+
+Options:
+  --config <path>          Config file to read (default config.toml in the config directory)
+  --port <port>            Port to listen on (default 1337)
+  --nick <nickname>        Nickname to use (default $LOCALCHAT_NICK or $USER)
+  --service-type <type>    DNS-SD service type (default _localchat._tcp). Give a subtype,
+                           e.g. _team-a._sub._localchat._tcp, to only see nodes using it
+  --domain <domain>        DNS-SD domain to advertise and browse in (default local.)
+  --discovery <backend>    How to find peers: dnssd or none (default dnssd)
+  --away-after <secs>      Go away after this long without input, 0 for never (default 300)
+  --relay                  Pass on messages between peers that can't reach each other
+  --on-key-change <what>   What to do when a peer's key isn't the one pinned for it: warn or
+                           refuse (default warn)
+  --messages-per-sec <n>   Messages each peer may send us per second, 0 for any (default 10)
+  --bytes-per-sec <n>      Bytes of messages each peer may send us per second, 0 for any
+                           (default 16384)
+  --on-rate-limit <what>   What to do with a peer sending faster: drop, delay, warn or
+                           disconnect (default warn)
+  --plain                  Plain lines instead of the full-screen interface
+  --                       Treat everything after as arguments, e.g. a message starting with --";
+
+/// The command line didn't make sense.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How peers are found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Discovery {
+    /// Advertise and browse with DNS-SD over multicast DNS
+    Dnssd,
+    /// Don't; peers have to find us
+    None,
+}
+
+impl Discovery {
+    pub fn parse(discovery: &str) -> Option<Self> {
+        match discovery {
+            "dnssd" | "mdns" => Some(Discovery::Dnssd),
+            "none" => Some(Discovery::None),
+            _ => None,
+        }
+    }
+}
+
+/// What to do.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Chat,
+    Listen,
+    Peers { wait: Duration },
+    Send { peer: String, message: String },
+    History { count: usize },
+    KnownPeers,
+    Trust { servicename: String },
+    Config,
+    Help,
+}
+
+/// Settings that apply whatever the command. Those left out come from the config file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Options {
+    pub config: Option<PathBuf>,
+    pub port: Option<u16>,
+    pub nick: Option<String>,
+    pub service_type: Option<ServiceType>,
+    pub domain: Option<String>,
+    pub discovery: Option<Discovery>,
+    /// `Some(None)` never goes away
+    pub away_after: Option<Option<Duration>>,
+    pub relay: bool,
+    pub on_key_change: Option<OnKeyChange>,
+    pub messages_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u32>,
+    pub on_rate_limit: Option<Violation>,
+    pub plain: bool,
+}
+
+fn parse_number<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error(format!("{} must be a number, not {:?}", name, value)))
+}
+
+/// Parses the arguments after the program's name. Options may come before or after the
+/// command, as `--name value` or `--name=value`, up to a `--`.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<(Command, Options), Error> {
+    let mut options = Options::default();
+    let mut words = Vec::new();
+    let mut wait = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            words.extend(args.by_ref());
+            break;
+        }
+        if !arg.starts_with("--") || arg == "--history" {
+            words.push(arg);
+            continue;
+        }
+        let (name, inline) = match arg.find('=') {
+            Some(equals) => (arg[..equals].to_owned(), Some(arg[equals + 1..].to_owned())),
+            None => (arg.clone(), None),
+        };
+        if name == "--plain" {
+            options.plain = true;
+            continue;
+        }
+        if name == "--relay" {
+            options.relay = true;
+            continue;
+        }
+        if name == "--help" {
+            return Ok((Command::Help, options));
+        }
+        let value = match inline.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(Error(format!("{} needs a value", name))),
+        };
+        match name.as_str() {
+            "--config" => options.config = Some(PathBuf::from(value)),
+            "--port" => match parse_number("--port", &value)? {
+                0 => return Err(Error("--port must be a port number, not 0".to_owned())),
+                port => options.port = Some(port),
+            },
+            "--nick" => options.nick = Some(value),
+            "--service-type" => {
+                options.service_type = Some(
+                    ServiceType::parse(&value)
+                        .ok_or_else(|| Error(format!("Not a service type: {}", value)))?,
+                )
+            }
+            "--domain" => options.domain = Some(value),
+            "--discovery" => {
+                options.discovery = Some(
+                    Discovery::parse(&value)
+                        .ok_or_else(|| Error(format!("Unknown discovery backend: {}", value)))?,
+                )
+            }
+            "--away-after" => {
+                let secs = parse_number("--away-after", &value)?;
+                options.away_after = Some(if secs == 0 {
+                    None
+                } else {
+                    Some(Duration::from_secs(secs))
+                });
+            }
+            "--on-key-change" => {
+                options.on_key_change = Some(OnKeyChange::parse(&value).ok_or_else(|| {
+                    Error(format!(
+                        "--on-key-change must be warn or refuse, not {:?}",
+                        value
+                    ))
+                })?)
+            }
+            "--messages-per-sec" => {
+                options.messages_per_sec = Some(parse_number("--messages-per-sec", &value)?)
+            }
+            "--bytes-per-sec" => {
+                options.bytes_per_sec = Some(parse_number("--bytes-per-sec", &value)?)
+            }
+            "--on-rate-limit" => {
+                options.on_rate_limit = Some(Violation::parse(&value).ok_or_else(|| {
+                    Error(format!(
+                        "--on-rate-limit must be drop, delay, warn or disconnect, not {:?}",
+                        value
+                    ))
+                })?)
+            }
+            "--wait" => wait = Some(Duration::from_secs(parse_number("--wait", &value)?)),
+            _ => return Err(Error(format!("Unknown option: {}", name))),
+        }
+    }
+
+    let mut words = words.into_iter();
+    let command = match words.next().as_deref() {
+        None | Some("chat") => Command::Chat,
+        Some("listen") => Command::Listen,
+        Some("peers") => Command::Peers {
+            wait: wait.unwrap_or(DEFAULT_PEERS_WAIT),
+        },
+        Some("send") => {
+            let peer = words.next();
+            let message = words.by_ref().collect::<Vec<_>>().join(" ");
+            match peer {
+                Some(peer) if !message.is_empty() => Command::Send { peer, message },
+                _ => return Err(Error("Usage: localchat send <peer> <message>".to_owned())),
+            }
+        }
+        // `--history` is how it used to be spelled
+        Some("history") | Some("--history") => Command::History {
+            count: match words.next() {
+                Some(count) => parse_number("count", &count)?,
+                None => 50,
+            },
+        },
+        Some("known-peers") => Command::KnownPeers,
+        Some("trust") => match words.next() {
+            Some(servicename) => Command::Trust { servicename },
+            None => return Err(Error("Usage: localchat trust <service name>".to_owned())),
+        },
+        Some("config") => Command::Config,
+        Some("help") => Command::Help,
+        Some(other) => return Err(Error(format!("Unknown command: {}", other))),
+    };
+    if let Some(extra) = words.next() {
+        return Err(Error(format!("Unexpected argument: {}", extra)));
+    }
+    if wait.is_some() && !matches!(command, Command::Peers { .. }) {
+        return Err(Error("--wait only applies to peers".to_owned()));
+    }
+    Ok((command, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<(Command, Options), Error> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse_args(args).unwrap().0
+    }
+
+    fn error(args: &[&str]) -> String {
+        parse_args(args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn subcommands() {
+        assert_eq!(command(&[]), Command::Chat);
+        assert_eq!(command(&["chat"]), Command::Chat);
+        assert_eq!(command(&["listen"]), Command::Listen);
+        assert_eq!(
+            command(&["peers"]),
+            Command::Peers {
+                wait: DEFAULT_PEERS_WAIT
+            }
+        );
+        assert_eq!(
+            command(&["send", "bob", "hello", "there"]),
+            Command::Send {
+                peer: "bob".to_owned(),
+                message: "hello there".to_owned(),
+            }
+        );
+        assert_eq!(command(&["history"]), Command::History { count: 50 });
+        assert_eq!(command(&["--history", "5"]), Command::History { count: 5 });
+        assert_eq!(command(&["known-peers"]), Command::KnownPeers);
+        assert_eq!(
+            command(&["trust", "alice@host"]),
+            Command::Trust {
+                servicename: "alice@host".to_owned()
+            }
+        );
+        assert_eq!(command(&["config"]), Command::Config);
+        assert_eq!(command(&["help"]), Command::Help);
+        assert_eq!(command(&["listen", "--help"]), Command::Help);
+    }
+
+    #[test]
+    fn options_go_before_or_after_the_command_in_either_form() {
+        let (command, options) = parse_args(&[
+            "--port",
+            "4000",
+            "peers",
+            "--wait=10",
+            "--nick=alice",
+            "--service-type",
+            "_team._sub._localchat._tcp",
+            "--domain=example.org.",
+            "--discovery",
+            "none",
+            "--config",
+            "/tmp/config.toml",
+            "--away-after=0",
+            "--relay",
+            "--on-key-change=refuse",
+            "--messages-per-sec=5",
+            "--bytes-per-sec",
+            "0",
+            "--on-rate-limit",
+            "disconnect",
+            "--plain",
+        ])
+        .unwrap();
+        assert_eq!(
+            command,
+            Command::Peers {
+                wait: Duration::from_secs(10)
+            }
+        );
+        assert_eq!(
+            options,
+            Options {
+                config: Some(PathBuf::from("/tmp/config.toml")),
+                port: Some(4000),
+                nick: Some("alice".to_owned()),
+                service_type: ServiceType::parse("_team._sub._localchat._tcp"),
+                domain: Some("example.org.".to_owned()),
+                discovery: Some(Discovery::None),
+                away_after: Some(None),
+                relay: true,
+                on_key_change: Some(OnKeyChange::Refuse),
+                messages_per_sec: Some(5),
+                bytes_per_sec: Some(0),
+                on_rate_limit: Some(Violation::Disconnect),
+                plain: true,
+            }
+        );
+    }
+
+    #[test]
+    fn an_equals_sign_only_splits_once() {
+        let (_, options) = parse_args(&["--nick=a=b"]).unwrap();
+        assert_eq!(options.nick, Some("a=b".to_owned()));
+    }
+
+    #[test]
+    fn a_double_dash_ends_the_options() {
+        assert_eq!(
+            command(&["send", "--port", "1", "bob", "--", "--hi", "--plain"]),
+            Command::Send {
+                peer: "bob".to_owned(),
+                message: "--hi --plain".to_owned(),
+            }
+        );
+        assert_eq!(
+            command(&["--", "trust", "--weird"]),
+            Command::Trust {
+                servicename: "--weird".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn port_zero_is_rejected_as_in_the_config_file() {
+        assert_eq!(error(&["--port=0"]), "--port must be a port number, not 0");
+        assert_eq!(
+            error(&["--port", "0"]),
+            "--port must be a port number, not 0"
+        );
+        assert_eq!(parse_args(&["--port=1"]).unwrap().1.port, Some(1));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(&["--port"]), "--port needs a value");
+        assert_eq!(
+            error(&["--port", "lots"]),
+            "--port must be a number, not \"lots\""
+        );
+        assert_eq!(
+            error(&["--port=70000"]),
+            "--port must be a number, not \"70000\""
+        );
+        assert_eq!(error(&["--colour", "red"]), "Unknown option: --colour");
+        assert_eq!(
+            error(&["--discovery=carrier-pigeon"]),
+            "Unknown discovery backend: carrier-pigeon"
+        );
+        assert_eq!(
+            error(&["--service-type", "localchat"]),
+            "Not a service type: localchat"
+        );
+        assert_eq!(
+            error(&["--on-key-change", "ignore"]),
+            "--on-key-change must be warn or refuse, not \"ignore\""
+        );
+        assert_eq!(
+            error(&["--on-rate-limit", "explode"]),
+            "--on-rate-limit must be drop, delay, warn or disconnect, not \"explode\""
+        );
+        assert_eq!(error(&["dance"]), "Unknown command: dance");
+        assert_eq!(error(&["listen", "now"]), "Unexpected argument: now");
+        assert_eq!(
+            error(&["send", "bob"]),
+            "Usage: localchat send <peer> <message>"
+        );
+        assert_eq!(error(&["trust"]), "Usage: localchat trust <service name>");
+        assert_eq!(
+            error(&["history", "all"]),
+            "count must be a number, not \"all\""
+        );
+        assert_eq!(
+            error(&["listen", "--wait", "5"]),
+            "--wait only applies to peers"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A command was given arguments it can't make sense of.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Usage;
+
+/// Why a line couldn't be run as a command.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No command goes by the name
+    Unknown(String),
+    /// The arguments didn't fit; holds how the command is meant to be used
+    Usage(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Unknown(ref name) => write!(f, "Unknown command: /{}", name),
+            Error::Usage(ref usage) => write!(f, "Usage: {}", usage),
+        }
+    }
+}
+
+/// What follows a command's name, taken a word at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(args: &'a str) -> Self {
+        Args { rest: args.trim() }
+    }
+
+    /// Takes the next word, if there is one.
+    pub fn word(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let (word, rest) = match self.rest.find(char::is_whitespace) {
+            Some(end) => (&self.rest[..end], &self.rest[end..]),
+            None => (self.rest, ""),
+        };
+        self.rest = rest.trim_start();
+        Some(word)
+    }
+
+    /// Takes the next word, which the command can't do without.
+    pub fn required(&mut self) -> Result<&'a str, Usage> {
+        self.word().ok_or(Usage)
+    }
+
+    /// Everything not yet taken, e.g. the text of a message.
+    pub fn rest(&self) -> &'a str {
+        self.rest
+    }
+
+    /// Everything not yet taken, which mustn't be empty.
+    pub fn rest_required(&self) -> Result<&'a str, Usage> {
+        if self.rest.is_empty() {
+            Err(Usage)
+        } else {
+            Ok(self.rest)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+}
+
+/// Whether `line` is meant as a command rather than something to say.
+pub fn is_command(line: &str) -> bool {
+    line.starts_with('/')
+}
+
+type Handler<C> = Box<dyn Fn(&mut C, Args) -> Result<(), Usage> + Send + Sync>;
+
+/// A command, run by typing `/` and its name.
+pub struct Command<C> {
+    pub name: String,
+    /// How it's used, e.g. `/msg <peer> <text>`
+    pub usage: String,
+    /// A line on what it does
+    pub help: String,
+    handler: Handler<C>,
+}
+
+impl<C> fmt::Debug for Command<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Command")
+            .field("name", &self.name)
+            .field("usage", &self.usage)
+            .finish()
+    }
+}
+
+/// The commands a client understands, each acting on a context of type `C`.
+///
+/// The client registers its own; anything built on the library can register more alongside, or
+/// in place of, them.
+#[derive(Debug)]
+pub struct Commands<C> {
+    commands: BTreeMap<String, Command<C>>,
+}
+
+impl<C> Default for Commands<C> {
+    fn default() -> Self {
+        Commands {
+            commands: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C> Commands<C> {
+    pub fn new() -> Self {
+        Commands::default()
+    }
+
+    /// Adds a command, replacing any by the same name. `name` doesn't include the `/`.
+    pub fn register<F>(&mut self, name: &str, usage: &str, help: &str, handler: F)
+    where
+        F: Fn(&mut C, Args) -> Result<(), Usage> + Send + Sync + 'static,
+    {
+        let command = Command {
+            name: name.to_owned(),
+            usage: usage.to_owned(),
+            help: help.to_owned(),
+            handler: Box::new(handler),
+        };
+        self.commands.insert(name.to_owned(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command<C>> {
+        self.commands.get(name.strip_prefix('/').unwrap_or(name))
+    }
+
+    /// All the commands, by name.
+    pub fn iter(&self) -> impl Iterator<Item = &Command<C>> {
+        self.commands.values()
+    }
+
+    /// Runs the command `line` names, with the rest of `line` as its arguments.
+    pub fn dispatch(&self, context: &mut C, line: &str) -> Result<(), Error> {
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let (name, args) = match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], &line[end..]),
+            None => (line, ""),
+        };
+        let command = self
+            .commands
+            .get(name)
+            .ok_or_else(|| Error::Unknown(name.to_owned()))?;
+        (command.handler)(context, Args::new(args))
+            .map_err(|Usage| Error::Usage(command.usage.clone()))
+    }
+
+    /// Completes the last word of `line`: a command's name if it's the first word, and one of
+    /// `names` otherwise. Returns the completed line, or `None` if there's nothing to add.
+    ///
+    /// A single match is completed along with a space after it; several are completed as far as
+    /// they agree.
+    pub fn complete(&self, line: &str, names: &[String]) -> Option<String> {
+        let start = line.rfind(' ').map(|space| space + 1).unwrap_or(0);
+        let (before, word) = line.split_at(start);
+        let candidates: Vec<String> = if start == 0 && is_command(word) {
+            self.commands
+                .keys()
+                .map(|name| format!("/{}", name))
+                .collect()
+        } else {
+            names.to_vec()
+        };
+        let lower = word.to_lowercase();
+        let matches: Vec<&String> = candidates
+            .iter()
+            .filter(|candidate| candidate.to_lowercase().starts_with(&lower))
+            .collect();
+        let completed = match matches.len() {
+            0 => return None,
+            1 => format!("{} ", matches[0]),
+            _ => common_prefix(&matches),
+        };
+        if completed.chars().count() <= word.chars().count() {
+            return None;
+        }
+        Some(format!("{}{}", before, completed))
+    }
+}
+
+/// The longest start that all of `words` share, ignoring case.
+fn common_prefix(words: &[&String]) -> String {
+    let mut rest: Vec<_> = words[1..].iter().map(|word| word.chars()).collect();
+    let mut prefix = String::new();
+    for c in words[0].chars() {
+        let agree = rest.iter_mut().all(|chars| {
+            chars
+                .next()
+                .is_some_and(|other| other.to_lowercase().eq(c.to_lowercase()))
+        });
+        if !agree {
+            break;
+        }
+        prefix.push(c);
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records what each command was run with.
+    type Log = Vec<String>;
+
+    fn commands() -> Commands<Log> {
+        let mut commands = Commands::new();
+        commands.register(
+            "msg",
+            "/msg <peer> <text>",
+            "Message a peer",
+            |log: &mut Log, mut args| {
+                let peer = args.required()?;
+                let text = args.rest_required()?;
+                log.push(format!("msg {} {}", peer, text));
+                Ok(())
+            },
+        );
+        commands.register("me", "/me <action>", "Act", |log: &mut Log, args| {
+            log.push(format!("me {}", args.rest()));
+            Ok(())
+        });
+        commands.register(
+            "members",
+            "/members",
+            "List members",
+            |log: &mut Log, args| {
+                if !args.is_empty() {
+                    return Err(Usage);
+                }
+                log.push("members".to_owned());
+                Ok(())
+            },
+        );
+        commands
+    }
+
+    #[test]
+    fn args_are_taken_a_word_at_a_time() {
+        let mut args = Args::new("  bob   hello  there  ");
+        assert_eq!(args.word(), Some("bob"));
+        assert_eq!(args.rest(), "hello  there");
+        assert_eq!(args.required(), Ok("hello"));
+        assert_eq!(args.rest_required(), Ok("there"));
+        assert_eq!(args.word(), Some("there"));
+        assert!(args.is_empty());
+        assert_eq!(args.word(), None);
+        assert_eq!(args.required(), Err(Usage));
+        assert_eq!(args.rest_required(), Err(Usage));
+    }
+
+    #[test]
+    fn dispatch_runs_the_named_command_with_its_arguments() {
+        let commands = commands();
+        let mut log = Log::new();
+        assert_eq!(commands.dispatch(&mut log, "/msg bob hi there"), Ok(()));
+        assert_eq!(commands.dispatch(&mut log, "/me"), Ok(()));
+        assert_eq!(commands.dispatch(&mut log, "/members"), Ok(()));
+        assert_eq!(log, vec!["msg bob hi there", "me ", "members"]);
+    }
+
+    #[test]
+    fn dispatch_reports_unknown_commands_and_bad_arguments() {
+        let commands = commands();
+        let mut log = Log::new();
+        assert_eq!(
+            commands.dispatch(&mut log, "/nope"),
+            Err(Error::Unknown("nope".to_owned()))
+        );
+        assert_eq!(
+            commands.dispatch(&mut log, "/msg bob"),
+            Err(Error::Usage("/msg <peer> <text>".to_owned()))
+        );
+        assert_eq!(
+            commands
+                .dispatch(&mut log, "/members now")
+                .unwrap_err()
+                .to_string(),
+            "Usage: /members"
+        );
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn only_one_slash_is_stripped() {
+        let commands = commands();
+        let mut log = Log::new();
+        assert_eq!(
+            commands.dispatch(&mut log, "//me waves"),
+            Err(Error::Unknown("/me".to_owned()))
+        );
+        assert!(commands.get("/me").is_some());
+        assert!(commands.get("//me").is_none());
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn is_command_looks_for_a_slash() {
+        assert!(is_command("/msg"));
+        assert!(!is_command("hello /msg"));
+        assert!(!is_command(""));
+    }
+
+    #[test]
+    fn complete_finishes_a_single_match_with_a_space() {
+        let commands = commands();
+        assert_eq!(commands.complete("/ms", &[]), Some("/msg ".to_owned()));
+        let names = vec!["Alice".to_owned(), "bob".to_owned()];
+        assert_eq!(
+            commands.complete("/msg al", &names),
+            Some("/msg Alice ".to_owned())
+        );
+        assert_eq!(
+            commands.complete("hi B", &names),
+            Some("hi bob ".to_owned())
+        );
+    }
+
+    #[test]
+    fn complete_goes_as_far_as_several_matches_agree() {
+        let commands = commands();
+        assert_eq!(commands.complete("/mem", &[]), Some("/members ".to_owned()));
+        let names = vec!["Carol".to_owned(), "CARLOS".to_owned()];
+        assert_eq!(commands.complete("c", &names), Some("Car".to_owned()));
+        let names = vec!["alice".to_owned(), "alicia".to_owned()];
+        assert_eq!(
+            commands.complete("/msg ali", &names),
+            Some("/msg alic".to_owned())
+        );
+    }
+
+    #[test]
+    fn complete_gives_up_when_there_is_nothing_to_add() {
+        let commands = commands();
+        let names = vec!["alice".to_owned(), "alicia".to_owned()];
+        // `/me`, `/members` and `/msg` agree on no more than what's there
+        assert_eq!(commands.complete("/m", &[]), None);
+        assert_eq!(commands.complete("/x", &[]), None);
+        assert_eq!(commands.complete("alic", &names), None);
+        assert_eq!(commands.complete("hi zed", &names), None);
+    }
+
+    #[test]
+    fn common_prefix_ignores_case_and_respects_characters() {
+        let words = ["Émile".to_owned(), "émilie".to_owned()];
+        let words: Vec<&String> = words.iter().collect();
+        assert_eq!(common_prefix(&words), "Émil");
+    }
+}
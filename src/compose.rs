@@ -0,0 +1,185 @@
+/// What to do with a line of input, once the composer has seen it.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Input {
+    /// An ordinary line, to be handled as usual
+    Line(String),
+    /// A finished multi-line message, to be sent as one
+    Message(String),
+    /// The line opened a code block, which the following lines go into.
+    Started,
+    /// The line went into the message being composed.
+    Pending,
+    /// The message being composed was thrown away.
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct Draft {
+    lines: Vec<String>,
+    /// Whether the draft is a code block, which ends at the closing fence rather than a dot
+    fenced: bool,
+}
+
+/// Gathers lines typed or pasted one at a time into multi-line messages.
+///
+/// `/compose` starts a message that ends at a line holding only a `.`. A line starting with
+/// ```` ``` ```` starts a code block that ends at the closing ```` ``` ````, fences included,
+/// unless the line closes it again itself. Either can be abandoned with `/cancel`.
+#[derive(Debug, Default)]
+pub struct Composer {
+    draft: Option<Draft>,
+}
+
+impl Composer {
+    pub fn new() -> Self {
+        Composer::default()
+    }
+
+    /// Starts composing a message that ends at a line holding only a `.`.
+    pub fn start(&mut self) {
+        self.draft = Some(Draft {
+            lines: Vec::new(),
+            fenced: false,
+        });
+    }
+
+    pub fn is_composing(&self) -> bool {
+        self.draft.is_some()
+    }
+
+    pub fn feed(&mut self, line: String) -> Input {
+        let mut draft = match self.draft.take() {
+            Some(draft) => draft,
+            None if opens_fence(&line) => {
+                self.draft = Some(Draft {
+                    lines: vec![line],
+                    fenced: true,
+                });
+                return Input::Started;
+            }
+            None => return Input::Line(line),
+        };
+        if line == "/cancel" {
+            return Input::Cancelled;
+        }
+        if draft.fenced && line.trim_end() == "```" {
+            draft.lines.push(line);
+            return Input::Message(draft.lines.join("\n"));
+        }
+        if !draft.fenced && line == "." {
+            if draft.lines.is_empty() {
+                return Input::Cancelled;
+            }
+            return Input::Message(draft.lines.join("\n"));
+        }
+        draft.lines.push(line);
+        self.draft = Some(draft);
+        Input::Pending
+    }
+}
+
+/// Whether `line` opens a code block that goes on past it. One that's closed on the same line,
+/// like ```` ```ls -la``` ````, is just a line.
+fn opens_fence(line: &str) -> bool {
+    let line = line.trim_end();
+    line.starts_with("```") && !(line.len() >= 6 && line.ends_with("```"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(composer: &mut Composer, lines: &[&str]) -> Vec<Input> {
+        lines
+            .iter()
+            .map(|line| composer.feed(line.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lines_pass_through_when_not_composing() {
+        let mut composer = Composer::new();
+        assert_eq!(composer.feed("hi".to_owned()), Input::Line("hi".to_owned()));
+        assert_eq!(composer.feed(".".to_owned()), Input::Line(".".to_owned()));
+        assert!(!composer.is_composing());
+    }
+
+    #[test]
+    fn compose_ends_at_a_lone_dot() {
+        let mut composer = Composer::new();
+        composer.start();
+        assert!(composer.is_composing());
+        let inputs = feed(&mut composer, &["first", "", "  second", "."]);
+        assert_eq!(
+            inputs,
+            vec![
+                Input::Pending,
+                Input::Pending,
+                Input::Pending,
+                Input::Message("first\n\n  second".to_owned()),
+            ]
+        );
+        assert!(!composer.is_composing());
+    }
+
+    #[test]
+    fn an_empty_compose_is_cancelled() {
+        let mut composer = Composer::new();
+        composer.start();
+        assert_eq!(composer.feed(".".to_owned()), Input::Cancelled);
+        assert!(!composer.is_composing());
+    }
+
+    #[test]
+    fn a_fence_starts_a_code_block_that_keeps_its_fences() {
+        let mut composer = Composer::new();
+        let inputs = feed(&mut composer, &["```rust", "fn main() {}", ".", "```"]);
+        assert_eq!(
+            inputs,
+            vec![
+                Input::Started,
+                Input::Pending,
+                Input::Pending,
+                Input::Message("```rust\nfn main() {}\n.\n```".to_owned()),
+            ]
+        );
+        assert!(!composer.is_composing());
+    }
+
+    #[test]
+    fn a_fence_closed_on_the_same_line_is_just_a_line() {
+        let mut composer = Composer::new();
+        for line in &["```ls -la```", "``` ```", "``````", "```x``` "] {
+            assert_eq!(
+                composer.feed(line.to_string()),
+                Input::Line(line.to_string())
+            );
+            assert!(!composer.is_composing());
+        }
+        assert_eq!(
+            composer.feed("after".to_owned()),
+            Input::Line("after".to_owned())
+        );
+    }
+
+    #[test]
+    fn cancel_throws_the_draft_away() {
+        let mut composer = Composer::new();
+        composer.start();
+        assert_eq!(
+            feed(&mut composer, &["draft", "/cancel"]),
+            vec![Input::Pending, Input::Cancelled]
+        );
+        assert!(!composer.is_composing());
+
+        assert_eq!(
+            feed(&mut composer, &["```", "code", "/cancel", "after"]),
+            vec![
+                Input::Started,
+                Input::Pending,
+                Input::Cancelled,
+                Input::Line("after".to_owned()),
+            ]
+        );
+    }
+}
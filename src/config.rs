@@ -0,0 +1,480 @@
+use dirs;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml;
+
+use chat::Heartbeat;
+use cli::{self, Discovery, Options};
+use dnssd::ServiceType;
+use known_peers::OnKeyChange;
+use ratelimit::{RateLimit, Violation};
+
+/// Commands to run when things happen. Each is run with `sh -c`, with `$LOCALCHAT_FROM`,
+/// `$LOCALCHAT_CHANNEL` and `$LOCALCHAT_BODY` describing the message.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Run for every message shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Run for private messages, after `message`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private: Option<String>,
+}
+
+/// Settings from the config file, with any given on the command line on top.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    pub nickname: Option<String>,
+    pub port: u16,
+    pub service_type: ServiceType,
+    /// The DNS-SD domain to advertise and browse in; the default domains if unset
+    pub domain: Option<String>,
+    pub discovery: Discovery,
+    /// Network interfaces to advertise, browse and listen on, by name; all of them if empty
+    pub interfaces: Vec<String>,
+    /// Peers to connect to whether or not they're discovered, as `host:port`
+    pub peers: Vec<String>,
+    pub history_path: Option<PathBuf>,
+    pub plain: bool,
+    pub hooks: Hooks,
+    /// How fast each peer may send us messages
+    pub rate_limit: RateLimit,
+    /// What to do when a peer's key isn't the one pinned for its service name
+    pub on_key_change: OnKeyChange,
+    /// How often to check that peers are still there
+    pub heartbeat: Heartbeat,
+    /// How long without input until we go away; never if `None`
+    pub away_after: Option<Duration>,
+    /// Whether to pass on messages between peers that can't reach each other
+    pub relay: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            nickname: None,
+            port: cli::DEFAULT_PORT,
+            service_type: ServiceType::parse(cli::DEFAULT_SERVICE_TYPE).unwrap(),
+            domain: None,
+            discovery: Discovery::Dnssd,
+            interfaces: Vec::new(),
+            peers: Vec::new(),
+            history_path: None,
+            plain: false,
+            hooks: Hooks::default(),
+            rate_limit: RateLimit::default(),
+            on_key_change: OnKeyChange::Warn,
+            heartbeat: Heartbeat::default(),
+            away_after: Some(Duration::from_secs(300)),
+            relay: false,
+        }
+    }
+}
+
+/// The config file as written, before it's checked and defaults are filled in.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discovery: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interfaces: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plain: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_key_change: Option<String>,
+    /// In seconds, with 0 for never
+    #[serde(skip_serializing_if = "Option::is_none")]
+    away_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relay: Option<bool>,
+    hooks: Hooks,
+    rate_limit: RateLimitFile,
+    heartbeat: HeartbeatFile,
+}
+
+/// The `[rate_limit]` table of the config file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages_per_sec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_per_sec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_violation: Option<String>,
+}
+
+/// The `[heartbeat]` table of the config file, in seconds.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeartbeatFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("localchat").join("config.toml"))
+    }
+
+    /// Loads the config file at `path`. A missing file is the default config.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match Config::read(path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            result => result,
+        }
+    }
+
+    /// Loads the config file at `path`, which has to exist.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let file: File = toml::from_str(text).map_err(|err| invalid(err.to_string()))?;
+        let defaults = Config::default();
+        let service_type = match file.service_type {
+            Some(service_type) => ServiceType::parse(&service_type).ok_or_else(|| {
+                invalid(format!(
+                    "service_type: not a service type: {:?}",
+                    service_type
+                ))
+            })?,
+            None => defaults.service_type,
+        };
+        let discovery = match file.discovery {
+            Some(discovery) => Discovery::parse(&discovery).ok_or_else(|| {
+                invalid(format!(
+                    "discovery: unknown discovery backend {:?}",
+                    discovery
+                ))
+            })?,
+            None => defaults.discovery,
+        };
+        let port = match file.port {
+            Some(0) => return Err(invalid("port: expected a port number, not 0".to_owned())),
+            Some(port) => port,
+            None => defaults.port,
+        };
+        let on_key_change = match file.on_key_change {
+            Some(on_key_change) => OnKeyChange::parse(&on_key_change).ok_or_else(|| {
+                invalid(format!(
+                    "on_key_change: expected warn or refuse, not {:?}",
+                    on_key_change
+                ))
+            })?,
+            None => defaults.on_key_change,
+        };
+        let on_violation = match file.rate_limit.on_violation {
+            Some(violation) => Violation::parse(&violation).ok_or_else(|| {
+                invalid(format!(
+                    "rate_limit.on_violation: expected drop, delay, warn or disconnect, not {:?}",
+                    violation
+                ))
+            })?,
+            None => defaults.rate_limit.on_violation,
+        };
+        let heartbeat = Heartbeat {
+            interval: file
+                .heartbeat
+                .interval
+                .map_or(defaults.heartbeat.interval, Duration::from_secs),
+            timeout: file
+                .heartbeat
+                .timeout
+                .map_or(defaults.heartbeat.timeout, Duration::from_secs),
+        };
+        if heartbeat.interval == Duration::from_secs(0) {
+            return Err(invalid(
+                "heartbeat.interval: expected a number of seconds, not 0".to_owned(),
+            ));
+        }
+        // The timeout is only checked when it's time to ping
+        if heartbeat.timeout < heartbeat.interval {
+            return Err(invalid(
+                "heartbeat.timeout: can't be shorter than the interval".to_owned(),
+            ));
+        }
+        Ok(Config {
+            nickname: file.nickname,
+            port,
+            service_type,
+            domain: file.domain,
+            discovery,
+            interfaces: file.interfaces.unwrap_or_default(),
+            peers: file.peers.unwrap_or_default(),
+            history_path: file.history_path.map(PathBuf::from),
+            plain: file.plain.unwrap_or(defaults.plain),
+            hooks: file.hooks,
+            rate_limit: RateLimit {
+                messages_per_sec: file
+                    .rate_limit
+                    .messages_per_sec
+                    .unwrap_or(defaults.rate_limit.messages_per_sec),
+                bytes_per_sec: file
+                    .rate_limit
+                    .bytes_per_sec
+                    .unwrap_or(defaults.rate_limit.bytes_per_sec),
+                on_violation,
+            },
+            on_key_change,
+            heartbeat,
+            away_after: match file.away_after {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => defaults.away_after,
+            },
+            relay: file.relay.unwrap_or(defaults.relay),
+        })
+    }
+
+    /// Lets what was given on the command line win over the config file.
+    pub fn apply(&mut self, options: &Options) {
+        if let Some(port) = options.port {
+            self.port = port;
+        }
+        if let Some(ref nick) = options.nick {
+            self.nickname = Some(nick.clone());
+        }
+        if let Some(ref service_type) = options.service_type {
+            self.service_type = service_type.clone();
+        }
+        if let Some(ref domain) = options.domain {
+            self.domain = Some(domain.clone());
+        }
+        if let Some(discovery) = options.discovery {
+            self.discovery = discovery;
+        }
+        if options.plain {
+            self.plain = true;
+        }
+        if let Some(away_after) = options.away_after {
+            self.away_after = away_after;
+        }
+        if options.relay {
+            self.relay = true;
+        }
+        if let Some(on_key_change) = options.on_key_change {
+            self.on_key_change = on_key_change;
+        }
+        if let Some(messages_per_sec) = options.messages_per_sec {
+            self.rate_limit.messages_per_sec = messages_per_sec;
+        }
+        if let Some(bytes_per_sec) = options.bytes_per_sec {
+            self.rate_limit.bytes_per_sec = bytes_per_sec;
+        }
+        if let Some(on_violation) = options.on_rate_limit {
+            self.rate_limit.on_violation = on_violation;
+        }
+    }
+}
+
+/// Writes the config as TOML that `Config::parse` reads back. Settings that aren't set are left
+/// out.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let discovery = match self.discovery {
+            Discovery::Dnssd => "dnssd",
+            Discovery::None => "none",
+        };
+        let file = File {
+            nickname: self.nickname.clone(),
+            port: Some(self.port),
+            service_type: Some(self.service_type.to_string()),
+            domain: self.domain.clone(),
+            discovery: Some(discovery.to_owned()),
+            interfaces: Some(self.interfaces.clone()),
+            peers: Some(self.peers.clone()),
+            history_path: self
+                .history_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            plain: Some(self.plain),
+            on_key_change: Some(self.on_key_change.as_str().to_owned()),
+            away_after: Some(self.away_after.map_or(0, |away_after| away_after.as_secs())),
+            relay: Some(self.relay),
+            hooks: self.hooks.clone(),
+            rate_limit: RateLimitFile {
+                messages_per_sec: Some(self.rate_limit.messages_per_sec),
+                bytes_per_sec: Some(self.rate_limit.bytes_per_sec),
+                on_violation: Some(self.rate_limit.on_violation.as_str().to_owned()),
+            },
+            heartbeat: HeartbeatFile {
+                interval: Some(self.heartbeat.interval.as_secs()),
+                timeout: Some(self.heartbeat.timeout.as_secs()),
+            },
+        };
+        let text = toml::to_string(&file).map_err(|_| fmt::Error)?;
+        f.write_str(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_empty_file_is_the_default_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parses_every_setting() {
+        let config = Config::parse(
+            r#"
+            nickname = "al\u00efce" # with a comment
+            port = 4_000
+            service_type = "_team._sub._localchat._tcp"
+            domain = 'example.org.'
+            discovery = "none"
+            interfaces = [
+                "eth0",
+                "wlan0", # trailing comma
+            ]
+            peers = ["10.0.0.2:1337"]
+            history_path = "/tmp/history"
+            plain = true
+            on_key_change = "refuse"
+            away_after = 0
+            relay = true
+
+            [hooks]
+            message = "notify-send \"$LOCALCHAT_FROM\""
+
+            [rate_limit]
+            messages_per_sec = 5
+            bytes_per_sec = 0
+            on_violation = "disconnect"
+
+            [heartbeat]
+            interval = 5
+            timeout = 15
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                nickname: Some("alïce".to_owned()),
+                port: 4000,
+                service_type: ServiceType::parse("_team._sub._localchat._tcp").unwrap(),
+                domain: Some("example.org.".to_owned()),
+                discovery: Discovery::None,
+                interfaces: vec!["eth0".to_owned(), "wlan0".to_owned()],
+                peers: vec!["10.0.0.2:1337".to_owned()],
+                history_path: Some(PathBuf::from("/tmp/history")),
+                plain: true,
+                hooks: Hooks {
+                    message: Some("notify-send \"$LOCALCHAT_FROM\"".to_owned()),
+                    private: None,
+                },
+                rate_limit: RateLimit {
+                    messages_per_sec: 5,
+                    bytes_per_sec: 0,
+                    on_violation: Violation::Disconnect,
+                },
+                on_key_change: OnKeyChange::Refuse,
+                away_after: None,
+                relay: true,
+                heartbeat: Heartbeat {
+                    interval: Duration::from_secs(5),
+                    timeout: Duration::from_secs(15),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn hooks_can_be_dotted_keys_or_an_inline_table() {
+        let expected = Hooks {
+            message: Some("a".to_owned()),
+            private: Some("b".to_owned()),
+        };
+        let dotted = Config::parse("hooks.message = \"a\"\nhooks.private = \"b\"").unwrap();
+        assert_eq!(dotted.hooks, expected);
+        let inline = Config::parse("hooks = { message = \"a\", private = \"b\" }").unwrap();
+        assert_eq!(inline.hooks, expected);
+    }
+
+    #[test]
+    fn rejects_what_it_does_not_understand() {
+        for text in &[
+            "colour = \"red\"",
+            "[hooks]\nstartup = \"true\"",
+            "port = 0",
+            "port = 70000",
+            "port = \"1337\"",
+            "plain = 1",
+            "peers = \"10.0.0.2:1337\"",
+            "service_type = \"localchat\"",
+            "discovery = \"carrier-pigeon\"",
+            "nickname = \"unterminated",
+            "on_key_change = \"ignore\"",
+            "[rate_limit]\non_violation = \"explode\"",
+            "rate_limit.messages_per_sec = -1",
+            "heartbeat.interval = 0",
+            "away_after = -1",
+            "relay = \"1\"",
+            "[heartbeat]\ninterval = 60\ntimeout = 30",
+        ] {
+            let err = Config::parse(text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", text);
+        }
+    }
+
+    #[test]
+    fn display_reads_back() {
+        let mut config = Config::default();
+        assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+
+        config.nickname = Some("bob \"the\" \\ builder\n".to_owned());
+        config.port = 4711;
+        config.domain = Some("example.org.".to_owned());
+        config.discovery = Discovery::None;
+        config.interfaces = vec!["eth0".to_owned()];
+        config.peers = vec!["[fe80::1%eth0]:1337".to_owned()];
+        config.history_path = Some(PathBuf::from("/tmp/history"));
+        config.plain = true;
+        config.hooks.private = Some("printf '%s' \"$LOCALCHAT_BODY\"".to_owned());
+        config.rate_limit.on_violation = Violation::Delay;
+        config.on_key_change = OnKeyChange::Refuse;
+        config.heartbeat.timeout = Duration::from_secs(120);
+        config.relay = true;
+        assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+        config.away_after = None;
+        assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+    }
+
+    #[test]
+    fn only_a_missing_default_file_is_the_default_config() {
+        let path = Path::new("/nonexistent/localchat/config.toml");
+        assert_eq!(Config::load(path).unwrap(), Config::default());
+        assert_eq!(
+            Config::read(path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use identity::NodeId;
+use message::Message;
+
+/// Whether a message reached one of its recipients.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// Sent, but not yet acknowledged
+    Pending,
+    Delivered,
+    /// Couldn't be sent, or wasn't acknowledged in time
+    Failed,
+}
+
+#[derive(Debug)]
+struct Outgoing {
+    message: Message,
+    sent_at: Instant,
+    recipients: HashMap<NodeId, Status>,
+}
+
+/// A message that didn't reach everyone it was sent to.
+#[derive(Debug)]
+pub struct Undelivered {
+    pub message: Message,
+    /// The recipients that didn't acknowledge it. Empty if there was nobody to send it to.
+    pub recipients: Vec<NodeId>,
+}
+
+/// Tracks the delivery status of the messages we've sent, per recipient.
+#[derive(Debug)]
+pub struct Receipts {
+    /// How long a recipient has to acknowledge a message
+    timeout: Duration,
+    messages: HashMap<String, Outgoing>,
+}
+
+impl Receipts {
+    pub fn new(timeout: Duration) -> Self {
+        Receipts {
+            timeout,
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Starts tracking `message`. Each recipient comes with whether the message could be handed
+    /// to a connection to it; those it couldn't have failed already.
+    pub fn sent<I>(&mut self, message: &Message, recipients: I)
+    where
+        I: IntoIterator<Item = (NodeId, bool)>,
+    {
+        let recipients = recipients
+            .into_iter()
+            .map(|(node_id, sent)| {
+                let status = if sent {
+                    Status::Pending
+                } else {
+                    Status::Failed
+                };
+                (node_id, status)
+            })
+            .collect();
+        self.messages.insert(
+            message.id.clone(),
+            Outgoing {
+                message: message.clone(),
+                sent_at: Instant::now(),
+                recipients,
+            },
+        );
+    }
+
+    /// Records an acknowledgement of message `id` from `peer`. Returns `false` if we weren't
+    /// waiting for one.
+    pub fn acked(&mut self, id: &str, peer: &NodeId) -> bool {
+        let status = self
+            .messages
+            .get_mut(id)
+            .and_then(|outgoing| outgoing.recipients.get_mut(peer));
+        match status {
+            Some(status) if *status == Status::Pending => {
+                *status = Status::Delivered;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The status of message `id` for each of its recipients, while it's being tracked.
+    pub fn status(&self, id: &str) -> Option<&HashMap<NodeId, Status>> {
+        self.messages.get(id).map(|outgoing| &outgoing.recipients)
+    }
+
+    /// Fails every recipient that has run out of time to acknowledge, and stops tracking
+    /// messages that are settled. Returns the settled messages that didn't reach everyone.
+    pub fn expire(&mut self) -> Vec<Undelivered> {
+        let timeout = self.timeout;
+        let mut settled = Vec::new();
+        for (id, outgoing) in &mut self.messages {
+            if outgoing.sent_at.elapsed() >= timeout {
+                for status in outgoing.recipients.values_mut() {
+                    if *status == Status::Pending {
+                        *status = Status::Failed;
+                    }
+                }
+            }
+            if outgoing
+                .recipients
+                .values()
+                .all(|status| *status != Status::Pending)
+            {
+                settled.push(id.clone());
+            }
+        }
+        settled
+            .into_iter()
+            .filter_map(|id| self.messages.remove(&id))
+            .filter_map(|outgoing| {
+                let failed: Vec<NodeId> = outgoing
+                    .recipients
+                    .iter()
+                    .filter(|(_, status)| **status == Status::Failed)
+                    .map(|(node_id, _)| node_id.clone())
+                    .collect();
+                if failed.is_empty() && !outgoing.recipients.is_empty() {
+                    return None;
+                }
+                Some(Undelivered {
+                    message: outgoing.message,
+                    recipients: failed,
+                })
+            })
+            .collect()
+    }
+}
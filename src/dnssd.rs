@@ -1,14 +1,18 @@
 use libc::{
-    c_char, c_int, c_uchar, c_void, int32_t, sa_family_t, sockaddr, sockaddr_in, sockaddr_in6,
-    uint16_t, uint32_t, AF_INET,
+    c_char, c_int, c_uchar, c_void, freeifaddrs, getifaddrs, if_nametoindex, ifaddrs, int32_t,
+    sa_family_t, sockaddr, sockaddr_in, sockaddr_in6, uint16_t, uint32_t, AF_INET, AF_INET6,
 };
 use mio;
 use mio::unix::EventedFd;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::convert::From;
 use std::ffi::{CStr, CString};
+use std::fmt;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ptr;
+use std::slice;
 use std::sync::Mutex;
 use tokio::prelude::*;
 use tokio::reactor::PollEvented2;
@@ -19,6 +23,10 @@ pub enum DNSService {}
 
 pub type DNSServiceRef = *mut DNSService;
 
+pub enum DNSRecord {}
+
+pub type DNSRecordRef = *mut DNSRecord;
+
 #[derive(Debug)]
 pub struct BoxedDNSServiceRef(DNSServiceRef);
 
@@ -107,6 +115,7 @@ extern "C" fn dns_service_register_cb(
                 name: CStr::from_ptr(name).to_string_lossy().into_owned(),
                 regtype: CStr::from_ptr(regtype).to_string_lossy().into_owned(),
                 domain: CStr::from_ptr(domain).to_string_lossy().into_owned(),
+                interface: 0,
             })
         }
     } else {
@@ -114,10 +123,26 @@ extern "C" fn dns_service_register_cb(
     };
 }
 
+/// Turns an optional string into a C string, or a null pointer's worth of `None`.
+fn optional_c_string(value: Option<&str>) -> Result<Option<CString>, ServiceError> {
+    value
+        .map(|value| CString::new(value).map_err(|_| ServiceError::BadParam))
+        .transpose()
+}
+
+fn as_ptr_or_null(value: &Option<CString>) -> *const c_char {
+    value.as_ref().map_or(ptr::null(), |value| value.as_ptr())
+}
+
 pub fn dns_service_register(
+    scope: &Scope,
+    port: u16,
+    txt_record: &TxtRecord,
     service_result_mutex: &mut Mutex<Result<Service, ServiceError>>,
 ) -> Result<BoxedDNSServiceRef, ServiceError> {
-    let reg_type = CString::new("_localchat._tcp.").unwrap();
+    let reg_type = scope.service_type.reg_type()?;
+    let domain = optional_c_string(scope.domain.as_deref())?;
+    let txt_record = txt_record.to_bytes();
     let context = service_result_mutex as *mut _ as *mut c_void;
     unsafe {
         let mut sd_ref: DNSServiceRef = ptr::null_mut();
@@ -125,14 +150,15 @@ pub fn dns_service_register(
         let err = DNSServiceRegister(
             sd_ref_ptr,
             0,
-            0,
+            scope.interface,
             ptr::null(),
             reg_type.as_ptr(),
+            as_ptr_or_null(&domain),
             ptr::null(),
-            ptr::null(),
-            1337,
-            0,
-            ptr::null(),
+            // In network byte order
+            port.to_be(),
+            txt_record.len() as u16,
+            txt_record.as_ptr() as *const c_void,
             dns_service_register_cb,
             context,
         );
@@ -148,7 +174,7 @@ pub fn dns_service_register(
 extern "C" fn dns_service_browse_cb(
     _sd_ref: DNSServiceRef,
     flags: DNSServiceFlags,
-    _interface_index: uint32_t,
+    interface_index: u32,
     error_code: DNSServiceErrorType,
     name: *const c_char,
     regtype: *const c_char,
@@ -165,6 +191,7 @@ extern "C" fn dns_service_browse_cb(
                 name: CStr::from_ptr(name).to_string_lossy().into_owned(),
                 regtype: CStr::from_ptr(regtype).to_string_lossy().into_owned(),
                 domain: CStr::from_ptr(domain).to_string_lossy().into_owned(),
+                interface: interface_index,
             }
         };
         let event = if flags & 0x2 > 0 {
@@ -179,19 +206,21 @@ extern "C" fn dns_service_browse_cb(
 }
 
 pub fn dns_service_browse(
+    scope: &Scope,
     browse_event_result: &mut Mutex<Result<ServiceEvent, ServiceError>>,
 ) -> Result<BoxedDNSServiceRef, ServiceError> {
     let context = browse_event_result as *mut _ as *mut c_void;
+    let reg_type = scope.service_type.reg_type()?;
+    let domain = optional_c_string(scope.domain.as_deref())?;
     unsafe {
         let mut sd_ref: DNSServiceRef = ptr::null_mut();
         let sd_ref_ptr = &mut sd_ref as *mut DNSServiceRef;
-        let reg_type = CString::new("_localchat._tcp.").unwrap();
         let err = DNSServiceBrowse(
             sd_ref_ptr,
             0,
-            0,
+            scope.interface,
             reg_type.as_ptr(),
-            ptr::null(),
+            as_ptr_or_null(&domain),
             dns_service_browse_cb,
             context,
         );
@@ -212,12 +241,21 @@ extern "C" fn dns_service_resolve_reply(
     _fullname: *const c_char,
     hosttarget: *const c_char,
     port: uint16_t,
-    _txt_len: uint16_t,
-    _txt_record: *const c_uchar,
+    txt_len: uint16_t,
+    txt_record: *const c_uchar,
     context: *mut c_void,
 ) {
     let name = unsafe { CStr::from_ptr(hosttarget).to_string_lossy().into_owned() };
-    let host = Host { name, port };
+    let txt = if txt_record.is_null() {
+        TxtRecord::default()
+    } else {
+        TxtRecord::parse(unsafe { slice::from_raw_parts(txt_record, txt_len as usize) })
+    };
+    let host = Host {
+        name,
+        port: u16::from_be(port),
+        txt,
+    };
     let err = ServiceError::from(error_code);
     let host_result_mutex: &mut Mutex<Result<Host, ServiceError>> =
         unsafe { &mut *(context as *mut Mutex<Result<Host, ServiceError>>) };
@@ -244,7 +282,7 @@ pub fn dns_service_resolve(
         let err = DNSServiceResolve(
             sd_ref_ptr,
             flags,
-            0,
+            service.interface,
             name,
             regtype,
             domain,
@@ -339,6 +377,31 @@ pub fn dns_service_get_addr_info(
     }
 }
 
+/// Replaces the TXT record of a registered service.
+pub fn dns_service_update_record(
+    sd_ref: &BoxedDNSServiceRef,
+    txt_record: &TxtRecord,
+) -> Result<(), ServiceError> {
+    let txt_record = txt_record.to_bytes();
+    let err = unsafe {
+        DNSServiceUpdateRecord(
+            sd_ref.0,
+            // null means the service's primary TXT record
+            ptr::null_mut(),
+            0,
+            txt_record.len() as u16,
+            txt_record.as_ptr() as *const c_void,
+            0,
+        )
+    };
+    let err = ServiceError::from(err);
+    if let ServiceError::NoError = err {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
 pub fn dns_service_ref_socket(sd_ref: &BoxedDNSServiceRef) -> Result<dnssd_sock_t, ServiceError> {
     let sock_fd = unsafe { DNSServiceRefSockFD(sd_ref.0) };
     if sock_fd == -1 {
@@ -365,7 +428,7 @@ extern "C" {
     fn DNSServiceBrowse(
         sd_ref: *mut DNSServiceRef,
         flags: DNSServiceFlags,
-        interface_index: uint32_t,
+        interface_index: u32,
         regtype: *const c_char,
         domain: *const c_char,
         callBack: DNSServiceBrowseReply,
@@ -408,6 +471,15 @@ extern "C" {
         context: *mut c_void,
     ) -> DNSServiceErrorType;
 
+    fn DNSServiceUpdateRecord(
+        sd_ref: DNSServiceRef,
+        record_ref: DNSRecordRef,
+        flags: DNSServiceFlags,
+        rdlen: u16,
+        rdata: *const c_void,
+        ttl: u32,
+    ) -> DNSServiceErrorType;
+
     fn DNSServiceRefSockFD(sd_ref: DNSServiceRef) -> dnssd_sock_t;
 
     fn DNSServiceProcessResult(sd_ref: DNSServiceRef) -> DNSServiceErrorType;
@@ -519,12 +591,191 @@ pub struct Service {
     pub name: String,
     pub regtype: String,
     pub domain: String,
+    /// The index of the interface the service was found on
+    pub interface: u32,
+}
+
+/// A DNS-SD service type such as `_localchat._tcp`, optionally narrowed to a subtype such as
+/// `_team-a._sub._localchat._tcp`.
+///
+/// Nodes with a subtype register under it and browse only it, so separate groups on one network
+/// don't find each other.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServiceType {
+    /// The type itself, e.g. `_localchat._tcp`
+    pub base: String,
+    /// The subtype's label, e.g. `_team-a`
+    pub subtype: Option<String>,
+}
+
+/// Whether `label` is an underscore followed by letters, digits and hyphens.
+fn is_service_label(label: &str) -> bool {
+    let mut chars = label.chars();
+    chars.next() == Some('_')
+        && label.len() > 1
+        && label.len() <= 63
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+impl ServiceType {
+    /// Parses `_service._tcp`, `_sub._sub._service._tcp` or the `_service._tcp,_sub` form the
+    /// DNS-SD API takes. A trailing dot is allowed.
+    pub fn parse(service_type: &str) -> Option<Self> {
+        let service_type = service_type.trim_end_matches('.');
+        let (base, subtype) = if let Some(comma) = service_type.find(',') {
+            (&service_type[..comma], Some(&service_type[comma + 1..]))
+        } else if let Some(sub) = service_type.find("._sub.") {
+            (&service_type[sub + 6..], Some(&service_type[..sub]))
+        } else {
+            (service_type, None)
+        };
+        let mut labels = base.split('.');
+        match (labels.next(), labels.next(), labels.next()) {
+            (Some(name), Some("_tcp"), None) if is_service_label(name) => (),
+            _ => return None,
+        }
+        if !subtype.is_none_or(is_service_label) {
+            return None;
+        }
+        Some(ServiceType {
+            base: base.to_owned(),
+            subtype: subtype.map(str::to_owned),
+        })
+    }
+
+    /// The type as `DNSServiceRegister` and `DNSServiceBrowse` take it.
+    fn reg_type(&self) -> Result<CString, ServiceError> {
+        let reg_type = match self.subtype {
+            Some(ref subtype) => format!("{},{}", self.base, subtype),
+            None => self.base.clone(),
+        };
+        CString::new(reg_type).map_err(|_| ServiceError::BadParam)
+    }
+}
+
+impl fmt::Display for ServiceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.subtype {
+            Some(ref subtype) => write!(f, "{}._sub.{}", subtype, self.base),
+            None => write!(f, "{}", self.base),
+        }
+    }
+}
+
+/// Where to advertise our service and look for others.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Scope {
+    pub service_type: ServiceType,
+    /// `None` for the default domains
+    pub domain: Option<String>,
+    /// An interface index, or 0 for all interfaces
+    pub interface: u32,
+}
+
+/// The index of the network interface called `name`, if there is one.
+pub fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// The IPv4 and IPv6 addresses of the network interface called `name`, with port 0. Link-local
+/// IPv6 addresses are scoped to the interface, so they can be bound.
+pub fn interface_addresses(name: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut ifaddrs: *mut ifaddrs = ptr::null_mut();
+    if unsafe { getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut next = ifaddrs;
+    while !next.is_null() {
+        let ifaddr = unsafe { &*next };
+        next = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null()
+            || unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_bytes() != name.as_bytes()
+        {
+            continue;
+        }
+        match c_int::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+            AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                addrs.push(SocketAddr::V4(SocketAddrV4::new(ip, 0)));
+            }
+            AF_INET6 => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                addrs.push(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    0,
+                    0,
+                    addr.sin6_scope_id,
+                )));
+            }
+            _ => {}
+        }
+    }
+    unsafe { freeifaddrs(ifaddrs) };
+    Ok(addrs)
 }
 
 #[derive(Clone, Debug)]
 pub struct Host {
     pub name: String,
     pub port: u16,
+    pub txt: TxtRecord,
+}
+
+/// The key/value pairs published alongside a service.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TxtRecord {
+    entries: BTreeMap<String, String>,
+}
+
+impl TxtRecord {
+    pub fn new() -> Self {
+        TxtRecord::default()
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.entries.insert(key.to_owned(), value.to_owned());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|value| value.as_str())
+    }
+
+    /// Encodes the entries as a sequence of length-prefixed `key=value` strings (RFC 6763 §6).
+    /// Entries longer than 255 bytes can't be represented and are skipped.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (key, value) in &self.entries {
+            let entry = format!("{}={}", key, value);
+            if entry.len() <= 255 {
+                bytes.push(entry.len() as u8);
+                bytes.extend_from_slice(entry.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn parse(mut bytes: &[u8]) -> Self {
+        let mut txt = TxtRecord::default();
+        while let Some((&len, rest)) = bytes.split_first() {
+            let len = cmp::min(len as usize, rest.len());
+            let entry = String::from_utf8_lossy(&rest[..len]);
+            let mut parts = entry.splitn(2, '=');
+            if let Some(key) = parts.next() {
+                if !key.is_empty() {
+                    txt.insert(key, parts.next().unwrap_or(""));
+                }
+            }
+            bytes = &rest[len..];
+        }
+        txt
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -539,12 +790,24 @@ pub struct Registration {
     service: Service,
 }
 
+impl Registration {
+    /// The service as registered, under the name the daemon settled on.
+    pub fn service(&self) -> &Service {
+        &self.service
+    }
+
+    pub fn update_txt_record(&self, txt_record: &TxtRecord) -> Result<(), Error> {
+        Ok(dns_service_update_record(&self.sd_ref, txt_record)?)
+    }
+}
+
 impl Default for Service {
     fn default() -> Self {
         Service {
             name: String::new(),
             regtype: String::new(),
             domain: String::new(),
+            interface: 0,
         }
     }
 }
@@ -580,10 +843,14 @@ impl mio::Evented for Socket {
     }
 }
 
-pub fn register_service() -> Result<impl Future<Item = Registration, Error = Error>, Error> {
+pub fn register_service(
+    scope: &Scope,
+    port: u16,
+    txt_record: &TxtRecord,
+) -> Result<impl Future<Item = Registration, Error = Error>, Error> {
     let service_result_mutex: &'static mut Mutex<Result<Service, ServiceError>> =
         Box::leak(Box::new(Mutex::new(Ok(Service::default()))));
-    let sd_ref = dns_service_register(service_result_mutex)?;
+    let sd_ref = dns_service_register(scope, port, txt_record, service_result_mutex)?;
     let raw_fd = dns_service_ref_socket(&sd_ref)?;
     Ok(wait_for_socket(raw_fd).then(move |result| {
         result?;
@@ -597,13 +864,15 @@ pub fn register_service() -> Result<impl Future<Item = Registration, Error = Err
     }))
 }
 
-pub fn browse_services() -> Result<impl Stream<Item = ServiceEvent, Error = Error>, Error> {
+pub fn browse_services(
+    scope: &Scope,
+) -> Result<impl Stream<Item = ServiceEvent, Error = Error>, Error> {
     let browse_event: &'static mut Mutex<Result<ServiceEvent, ServiceError>> =
         Box::leak(Box::new(Mutex::new(Ok(ServiceEvent {
             service: Service::default(),
             event: NetworkEvent::Joined,
         }))));
-    let sd_ref = dns_service_browse(browse_event)?;
+    let sd_ref = dns_service_browse(scope, browse_event)?;
     let raw_fd = dns_service_ref_socket(&sd_ref)?;
     Ok(socket_ready_stream(raw_fd).then(move |result| {
         result?;
@@ -623,6 +892,7 @@ pub fn resolve_service(
         Box::leak(Box::new(Mutex::new(Ok(Host {
             name: String::new(),
             port: 0,
+            txt: TxtRecord::default(),
         }))));
     let sd_ref = dns_service_resolve(service, host_result_mutex)?;
     let raw_fd = dns_service_ref_socket(&sd_ref)?;
@@ -701,3 +971,72 @@ impl Stream for SocketReadyStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service_type(base: &str, subtype: Option<&str>) -> Option<ServiceType> {
+        Some(ServiceType {
+            base: base.to_owned(),
+            subtype: subtype.map(str::to_owned),
+        })
+    }
+
+    #[test]
+    fn parses_every_form_of_service_type() {
+        let plain = service_type("_localchat._tcp", None);
+        let team = service_type("_localchat._tcp", Some("_team-a"));
+        assert_eq!(ServiceType::parse("_localchat._tcp"), plain);
+        assert_eq!(ServiceType::parse("_localchat._tcp."), plain);
+        assert_eq!(ServiceType::parse("_team-a._sub._localchat._tcp"), team);
+        assert_eq!(ServiceType::parse("_team-a._sub._localchat._tcp."), team);
+        assert_eq!(ServiceType::parse("_localchat._tcp,_team-a"), team);
+    }
+
+    #[test]
+    fn rejects_bad_labels() {
+        for bad in &[
+            "",
+            ".",
+            "localchat",
+            "localchat._tcp",
+            "_localchat",
+            "_localchat._udp",
+            "_localchat._tcp._tcp",
+            "_._tcp",
+            "_local chat._tcp",
+            "_local.chat._tcp",
+            "team._sub._localchat._tcp",
+            "_team._sub.",
+            "_localchat._tcp,",
+            "_localchat._tcp,team",
+            "_localchat._tcp,_team,_other",
+            "_a._b._sub._localchat._tcp",
+        ] {
+            assert_eq!(ServiceType::parse(bad), None, "{:?}", bad);
+        }
+        let long = format!("_{}._tcp", "a".repeat(63));
+        assert_eq!(ServiceType::parse(&long), None);
+        let longest = format!("_{}._tcp", "a".repeat(62));
+        assert!(ServiceType::parse(&longest).is_some());
+    }
+
+    #[test]
+    fn displays_the_sub_form_and_registers_the_comma_form() {
+        let team = ServiceType::parse("_localchat._tcp,_team-a").unwrap();
+        assert_eq!(team.to_string(), "_team-a._sub._localchat._tcp");
+        assert_eq!(ServiceType::parse(&team.to_string()), Some(team.clone()));
+        assert_eq!(
+            team.reg_type().unwrap().to_str().unwrap(),
+            "_localchat._tcp,_team-a"
+        );
+
+        let plain = ServiceType::parse("_localchat._tcp.").unwrap();
+        assert_eq!(plain.to_string(), "_localchat._tcp");
+        assert_eq!(
+            plain.reg_type().unwrap().to_str().unwrap(),
+            "_localchat._tcp"
+        );
+    }
+}
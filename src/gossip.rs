@@ -0,0 +1,43 @@
+use std::collections::{HashSet, VecDeque};
+
+/// How many hops a message may take, counting the one from its author.
+pub const DEFAULT_TTL: u8 = 4;
+
+/// The ids of the messages we've seen lately, so that relayed copies are only handled once.
+///
+/// Only the most recent ids are remembered; by the time one is forgotten, its copies should
+/// have long stopped circulating.
+#[derive(Debug)]
+pub struct Seen {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl Seen {
+    pub fn new(capacity: usize) -> Self {
+        Seen {
+            capacity,
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Notes that we've seen message `id`. Returns `false` if we already had.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_owned()) {
+            return false;
+        }
+        self.order.push_back(id.to_owned());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
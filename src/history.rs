@@ -0,0 +1,185 @@
+use dirs;
+use serde_json;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use message::Message;
+
+/// Every message we've sent or received, in the order we saw them, kept in an append-only log.
+///
+/// The file has one message per line, as JSON, signature and all, so that anything read back
+/// can still be verified.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    messages: Vec<Message>,
+    /// The ids of `messages`, so that nothing is recorded twice
+    ids: HashSet<String>,
+    /// How many lines of the file couldn't be read
+    skipped: usize,
+}
+
+impl History {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("localchat").join("history"))
+    }
+
+    /// Opens the history at `path`. A missing file is an empty history.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut history = History {
+            path: path.to_owned(),
+            messages: Vec::new(),
+            ids: HashSet::new(),
+            skipped: 0,
+        };
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    match serde_json::from_str::<Message>(line) {
+                        Ok(message) => {
+                            if history.ids.insert(message.id.clone()) {
+                                history.messages.push(message);
+                            }
+                        }
+                        Err(_) => history.skipped += 1,
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(history)
+    }
+
+    /// Appends `message` to the history. Returns `false` if it was already there.
+    pub fn record(&mut self, message: &Message) -> io::Result<bool> {
+        if self.ids.contains(&message.id) {
+            return Ok(false);
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Private messages are only for our eyes, and those of their recipients
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        // The mode only applies to new files, and older versions made this one readable by all
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        // Serializing a `Message` can't fail: it's all strings and integers
+        writeln!(file, "{}", serde_json::to_string(message).unwrap())?;
+        self.ids.insert(message.id.clone());
+        self.messages.push(message.clone());
+        Ok(true)
+    }
+
+    /// How many malformed lines were skipped when the history was opened.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Up to `count` messages from just before the one with id `before`, or from the end if
+    /// that's `None`, oldest first. Pass the id of the first message of a page to get the page
+    /// before it. Returns `None` if there's no message with id `before`.
+    pub fn page(&self, before: Option<&str>, count: usize) -> Option<&[Message]> {
+        let end = match before {
+            Some(id) => self.messages.iter().position(|message| message.id == id)?,
+            None => self.messages.len(),
+        };
+        Some(&self.messages[end.saturating_sub(count)..end])
+    }
+
+    /// The last message in `channel`, or outside any channel if that's `None`.
+    pub fn latest(&self, channel: Option<&str>) -> Option<&Message> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| !message.is_private() && message.channel.as_deref() == channel)
+    }
+
+    /// Up to `limit` of the latest public messages in `channel` that came after the message with
+    /// id `after`, or, if we don't have that one, that were sent after `since` (in milliseconds
+    /// since the Unix epoch), and that come to no more than `max_bytes` of JSON. Oldest first.
+    pub fn since(
+        &self,
+        channel: Option<&str>,
+        since: u64,
+        after: Option<&str>,
+        limit: usize,
+        max_bytes: usize,
+    ) -> Vec<&Message> {
+        let start = after
+            .and_then(|id| self.messages.iter().position(|message| message.id == id))
+            .map(|position| position + 1);
+        let messages: Vec<&Message> = self.messages[start.unwrap_or(0)..]
+            .iter()
+            .filter(|message| !message.is_private() && message.channel.as_deref() == channel)
+            .filter(|message| start.is_some() || message.timestamp > since)
+            .collect();
+        let mut bytes = 0;
+        let mut latest: Vec<&Message> = messages
+            .into_iter()
+            .rev()
+            .take(limit)
+            .take_while(|message| {
+                // Serializing a `Message` can't fail: it's all strings and integers
+                bytes += serde_json::to_string(message).unwrap().len() + 1;
+                bytes <= max_bytes
+            })
+            .collect();
+        latest.reverse();
+        latest
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identity::Identity;
+    use rand;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir()
+            .join(format!("localchat-test-{}", rand::random::<u64>()))
+            .join(name)
+    }
+
+    #[test]
+    fn only_the_owner_can_read_the_history() {
+        let path = temp_path("history");
+        let mut history = History::open(&path).unwrap();
+        let identity = Identity::generate();
+        let message = Message::new(&identity, "alice".to_string(), 1, "hi".to_string());
+        assert!(history.record(&message).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A file left readable by an older version is tightened on the next write
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let message = Message::new(&identity, "alice".to_string(), 2, "again".to_string());
+        assert!(history.record(&message).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let history = History::open(&path).unwrap();
+        assert_eq!(history.len(), 2);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
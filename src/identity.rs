@@ -0,0 +1,195 @@
+use dirs;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hex;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// A stable identifier for a node, derived from its identity key. Unlike a service name or
+/// address, it survives renames and DHCP.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct NodeId(String);
+
+impl NodeId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'a> From<&'a str> for NodeId {
+    fn from(id: &'a str) -> Self {
+        NodeId(id.to_owned())
+    }
+}
+
+/// The public half of a node's identity.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != PUBLIC_KEY_LEN {
+            return None;
+        }
+        let mut key = [0u8; PUBLIC_KEY_LEN];
+        key.copy_from_slice(bytes);
+        VerifyingKey::from_bytes(&key).ok().map(PublicKey)
+    }
+
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.0.to_bytes()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        NodeId(hex::encode(&Sha256::digest(self.0.as_bytes())[..16]))
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        Signature::from_slice(signature)
+            .map(|signature| self.0.verify(data, &signature).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+/// The long-lived Ed25519 keypair that identifies this node to its peers.
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Where the identity is kept unless the caller says otherwise.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("localchat").join("identity"))
+    }
+
+    /// Loads the identity saved at `path`, generating and saving a new one on first run.
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                Identity::parse(contents.trim())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad identity key"))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                identity.save(path)?;
+                Ok(identity)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let bytes = hex::decode(contents).ok()?;
+        if bytes.len() != 32 {
+            return None;
+        }
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&bytes);
+        Some(Identity {
+            key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // The secret key is only for our eyes
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", hex::encode(self.key.to_bytes()))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key())
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.public_key().node_id()
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.key.sign(data).to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn an_identity_survives_a_restart() {
+        let dir = env::temp_dir().join(format!("localchat-test-{}", rand::random::<u64>()));
+        let path = dir.join("identity");
+        let first = Identity::load_or_generate(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let second = Identity::load_or_generate(&path).unwrap();
+        assert_eq!(second.node_id(), first.node_id());
+        assert_eq!(second.public_key(), first.public_key());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_identity_is_an_error_not_a_new_one() {
+        let dir = env::temp_dir().join(format!("localchat-test-{}", rand::random::<u64>()));
+        let path = dir.join("identity");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "not a key\n").unwrap();
+        let err = Identity::load_or_generate(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn signatures_only_verify_with_the_signer_and_the_data() {
+        let identity = Identity::generate();
+        let signature = identity.sign(b"hello");
+        let key = identity.public_key();
+        assert!(key.verify(b"hello", &signature));
+        assert!(!key.verify(b"hellO", &signature));
+        assert!(!key.verify(b"hello", &signature[1..]));
+        assert!(!Identity::generate()
+            .public_key()
+            .verify(b"hello", &signature));
+    }
+
+    #[test]
+    fn the_node_id_is_derived_from_the_public_key() {
+        let identity = Identity::generate();
+        let key = PublicKey::from_bytes(&identity.public_key().to_bytes()).unwrap();
+        assert_eq!(key.node_id(), identity.node_id());
+        assert_eq!(identity.node_id().as_str().len(), 32);
+        assert_ne!(Identity::generate().node_id(), identity.node_id());
+        assert!(PublicKey::from_bytes(&[0u8; PUBLIC_KEY_LEN - 1]).is_none());
+    }
+}
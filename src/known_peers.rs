@@ -0,0 +1,240 @@
+use dirs;
+use hex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use identity::PublicKey;
+
+/// What to do when a peer presents a different identity key than the one pinned for its service
+/// name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnKeyChange {
+    /// Complain loudly but carry on with the connection.
+    Warn,
+    /// Refuse the connection until the user re-trusts the peer.
+    Refuse,
+}
+
+impl OnKeyChange {
+    pub fn parse(on_key_change: &str) -> Option<Self> {
+        match on_key_change {
+            "warn" => Some(OnKeyChange::Warn),
+            "refuse" => Some(OnKeyChange::Refuse),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OnKeyChange::Warn => "warn",
+            OnKeyChange::Refuse => "refuse",
+        }
+    }
+}
+
+/// The outcome of checking a peer's key against the store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Trust {
+    /// First contact; the key has now been pinned.
+    New,
+    /// The key matches the pinned one.
+    Known,
+    /// The service name is pinned to a different key.
+    Changed { pinned: PublicKey },
+}
+
+/// Identity keys pinned on first use, keyed by service name, in the spirit of SSH's
+/// `known_hosts`.
+///
+/// The file has one peer per line: the hex-encoded public key, a space, then the service name.
+#[derive(Debug)]
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: BTreeMap<String, PublicKey>,
+    /// How many lines of the file couldn't be read
+    skipped: usize,
+}
+
+impl KnownPeers {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("localchat").join("known_peers"))
+    }
+
+    /// Loads the store at `path`. A missing file is an empty store.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut peers = BTreeMap::new();
+        let mut skipped = 0;
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let mut parts = line.splitn(2, ' ');
+                    let key = parts
+                        .next()
+                        .and_then(|key| hex::decode(key).ok())
+                        .and_then(|key| PublicKey::from_bytes(&key));
+                    match (key, parts.next()) {
+                        (Some(key), Some(servicename)) => {
+                            peers.insert(servicename.to_owned(), key);
+                        }
+                        _ => skipped += 1,
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(KnownPeers {
+            path: path.to_owned(),
+            peers,
+            skipped,
+        })
+    }
+
+    /// How many malformed lines were skipped when the store was loaded. They're lost when it's
+    /// next saved.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::File::create(&self.path)?;
+        for (servicename, key) in &self.peers {
+            writeln!(file, "{} {}", hex::encode(key.to_bytes()), servicename)?;
+        }
+        Ok(())
+    }
+
+    /// Checks `key` against the one pinned for `servicename`, pinning it if there isn't one.
+    pub fn check(&mut self, servicename: &str, key: &PublicKey) -> io::Result<Trust> {
+        match self.peers.get(servicename) {
+            Some(pinned) if pinned == key => return Ok(Trust::Known),
+            Some(pinned) => return Ok(Trust::Changed { pinned: *pinned }),
+            None => {}
+        }
+        self.peers.insert(servicename.to_owned(), *key);
+        self.save()?;
+        Ok(Trust::New)
+    }
+
+    /// Unpins `servicename`, so that whatever key it presents next is trusted. Returns whether it
+    /// was pinned.
+    pub fn forget(&mut self, servicename: &str) -> io::Result<bool> {
+        if self.peers.remove(servicename).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PublicKey)> {
+        self.peers.iter().map(|(name, key)| (name.as_str(), key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identity::Identity;
+    use rand;
+    use std::env;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir()
+            .join(format!("localchat-test-{}", rand::random::<u64>()))
+            .join("known_peers")
+    }
+
+    #[test]
+    fn keys_are_pinned_on_first_use() {
+        let path = temp_path();
+        let mut known_peers = KnownPeers::load(&path).unwrap();
+        let alice = Identity::generate().public_key();
+        let mallory = Identity::generate().public_key();
+
+        assert_eq!(known_peers.check("alice@host", &alice).unwrap(), Trust::New);
+        assert_eq!(
+            known_peers.check("alice@host", &alice).unwrap(),
+            Trust::Known
+        );
+        assert_eq!(
+            known_peers.check("alice@host", &mallory).unwrap(),
+            Trust::Changed { pinned: alice }
+        );
+        // A mismatch doesn't replace the pinned key
+        assert_eq!(
+            known_peers.check("alice@host", &alice).unwrap(),
+            Trust::Known
+        );
+        // Keys are pinned per service name
+        assert_eq!(
+            known_peers.check("mallory@host", &mallory).unwrap(),
+            Trust::New
+        );
+
+        // ... and survive a restart
+        let mut known_peers = KnownPeers::load(&path).unwrap();
+        assert_eq!(known_peers.skipped(), 0);
+        assert_eq!(
+            known_peers.check("alice@host", &alice).unwrap(),
+            Trust::Known
+        );
+        let names: Vec<&str> = known_peers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["alice@host", "mallory@host"]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn forgetting_a_peer_trusts_its_next_key() {
+        let path = temp_path();
+        let mut known_peers = KnownPeers::load(&path).unwrap();
+        let old = Identity::generate().public_key();
+        let new = Identity::generate().public_key();
+        known_peers.check("alice@host", &old).unwrap();
+
+        assert!(known_peers.forget("alice@host").unwrap());
+        assert!(!known_peers.forget("alice@host").unwrap());
+        assert_eq!(known_peers.check("alice@host", &new).unwrap(), Trust::New);
+
+        let mut known_peers = KnownPeers::load(&path).unwrap();
+        assert_eq!(known_peers.check("alice@host", &new).unwrap(), Trust::Known);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let path = temp_path();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let alice = Identity::generate().public_key();
+        let contents = format!(
+            "{} alice @ the host\nnot-hex bob@host\n{}\n\n",
+            hex::encode(alice.to_bytes()),
+            hex::encode(alice.to_bytes())
+        );
+        fs::write(&path, contents).unwrap();
+
+        let mut known_peers = KnownPeers::load(&path).unwrap();
+        assert_eq!(known_peers.skipped(), 3);
+        // Service names may have spaces in them
+        assert_eq!(
+            known_peers.check("alice @ the host", &alice).unwrap(),
+            Trust::Known
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn on_key_change_parses_what_it_prints() {
+        for on_key_change in &[OnKeyChange::Warn, OnKeyChange::Refuse] {
+            assert_eq!(
+                OnKeyChange::parse(on_key_change.as_str()),
+                Some(*on_key_change)
+            );
+        }
+        assert_eq!(OnKeyChange::parse("ignore"), None);
+    }
+}
@@ -1,13 +1,44 @@
 #[macro_use]
 extern crate futures;
 extern crate bytes;
+extern crate dirs;
+extern crate ed25519_dalek;
+extern crate hex;
 extern crate libc;
 extern crate mio;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+extern crate snow;
 extern crate tokio;
+extern crate toml;
 
+pub mod channels;
 pub mod chat;
+pub mod cli;
+pub mod commands;
+pub mod compose;
+pub mod config;
+pub mod delivery;
 pub mod dnssd;
+pub mod gossip;
+pub mod history;
+pub mod identity;
+pub mod known_peers;
+pub mod message;
+pub mod nicknames;
+pub mod ordering;
+pub mod outbox;
 pub mod peer;
+pub mod presence;
+pub mod ratelimit;
+pub mod reconnect;
+pub mod secure;
+pub mod transfer;
+pub mod tui;
 
 #[derive(Clone, Debug)]
 pub enum NetworkEvent {
@@ -1,105 +1,2148 @@
-extern crate bytes;
 extern crate futures;
 extern crate localchat;
 extern crate tokio;
 
-use bytes::Bytes;
-use futures::future::lazy;
+use futures::future::{self, lazy, Either, Loop};
+use futures::stream;
 use futures::sync::mpsc;
 use localchat::dnssd;
-use localchat::peer::{track_peers, Peer, PeerEvent};
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use localchat::peer::{track_peers, Peer, PeerEvent, GROUP_KEY};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::codec::{BytesCodec, FramedRead, LinesCodec};
 use tokio::prelude::*;
+use tokio::timer::{Delay, Interval};
 
+use localchat::channels::{self, Channels};
 use localchat::chat;
+use localchat::cli::{self, Discovery};
+use localchat::commands::{self, Args, Commands, Usage};
+use localchat::compose::{Composer, Input};
+use localchat::config::{Config, Hooks};
+use localchat::delivery::Receipts;
+use localchat::gossip::Seen;
+use localchat::history::History;
+use localchat::identity::{Identity, NodeId};
+use localchat::known_peers::KnownPeers;
+use localchat::message::{Frame, Message};
+use localchat::nicknames::{self, Nicknames};
+use localchat::ordering::{self, LamportClock, ReorderBuffer};
+use localchat::outbox::Outbox;
+use localchat::presence::{IdleTracker, Presence, Status};
+use localchat::reconnect::{self, Backoff, ConnectionState};
+use localchat::secure::Keys;
+use localchat::transfer::{self, Expected, Incoming, Offer, Outgoing, Signal};
+use localchat::tui::{Key, KeyParser, Screen, Terminal};
 use localchat::NetworkEvent;
 
+/// The full-screen interface, while it's up.
+struct Ui {
+    terminal: Terminal,
+    screen: Screen,
+    /// What tab completes to after a command
+    names: Vec<String>,
+}
+
+impl Ui {
+    fn draw(&self) {
+        let (width, height) = Terminal::size();
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(self.screen.render(width, height).as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// How long `localchat send` waits for the peer to turn up and acknowledge the message.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many queued messages a peer may have unacknowledged at once. Together with the frames
+/// that open a connection, that's well within its rate limit.
+const OUTBOX_WINDOW: usize = 3;
+
+/// The most history we send in answer to one request, leaving room in the frame for the rest.
+const MAX_HISTORY_BYTES: usize = chat::MAX_RECORD_LEN / 2;
+
+/// `None` in `--plain` mode, where output just goes to stdout.
+static UI: Mutex<Option<Ui>> = Mutex::new(None);
+
+/// Shows a line of output in the message pane, or prints it in `--plain` mode.
+fn show(text: String) {
+    let mut ui = UI.lock().unwrap();
+    match *ui {
+        Some(ref mut ui) => {
+            ui.screen.push_message(text);
+            ui.draw();
+        }
+        None => println!("{}", text),
+    }
+}
+
+macro_rules! say {
+    ($($arg:tt)*) => {
+        show(format!($($arg)*))
+    };
+}
+
+/// Puts the terminal back the way it was, and exits.
+fn quit() -> ! {
+    if let Some(ui) = UI.lock().unwrap().take() {
+        drop(ui.terminal);
+    }
+    process::exit(0);
+}
+
 #[derive(Debug)]
 struct State {
-    service_registration: Option<dnssd::Registration>,
-    peers: HashSet<Peer>,
+    /// One registration for each interface we advertise on
+    registrations: Vec<dnssd::Registration>,
+    txt_record: dnssd::TxtRecord,
+    peers: HashMap<NodeId, Peer>,
+    /// Where each peer has been found, by service name and interface. A node advertises one
+    /// service for each interface, and may be seen on each of ours, so it's only gone once
+    /// every one of them is.
+    instances: HashMap<NodeId, HashSet<(String, u32)>>,
+    connections: HashMap<NodeId, chat::Sender>,
+    /// Keeps the outbound connection to each peer open for as long as it's discovered
+    reconnectors: HashMap<NodeId, reconnect::Handle>,
+    channels: Channels,
+    nicknames: Nicknames,
+    /// Where lines typed without a command go. `None` is everyone, outside any channel.
+    current_channel: Option<String>,
+    presence: Presence,
+    idle: IdleTracker,
+    receipts: Receipts,
+    /// Direct messages for peers that weren't around when they were sent
+    outbox: Outbox,
+    /// The ids of the queued messages sent to each peer that it hasn't acknowledged yet
+    flushing: HashMap<NodeId, HashSet<String>>,
+    history: History,
+    clock: LamportClock,
+    node_id: NodeId,
+    /// Whether to pass on messages to peers that may not have them
+    relay: bool,
+    seen: Seen,
+    /// Received messages waiting to be shown in order
+    reorder: ReorderBuffer,
+    /// Files we're sending, by transfer id
+    outgoing_files: HashMap<String, Outgoing>,
+    /// Files we're receiving, by transfer id
+    incoming_files: HashMap<String, Incoming>,
+    /// The files in `incoming_files`, as connections see them
+    expected_files: Expected,
+    /// Files we've been offered and haven't answered yet, oldest first
+    offers: Vec<(NodeId, Offer)>,
+    download_dir: PathBuf,
+    composer: Composer,
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(
+        node: &chat::Node,
+        away_after: Option<Duration>,
+        outbox: Outbox,
+        history: History,
+        relay: bool,
+    ) -> Self {
+        let presence = Presence::online();
+        let mut txt_record = dnssd::TxtRecord::new();
+        txt_record.insert("fp", &node.keys.fingerprint());
+        txt_record.insert("id", node.identity.node_id().as_str());
+        txt_record.insert("ps", &presence.to_txt());
+        let mut nicknames = Nicknames::new();
+        nicknames.claim(node.identity.node_id(), node.nickname());
         State {
-            service_registration: None,
-            peers: HashSet::new(),
+            registrations: Vec::new(),
+            txt_record,
+            peers: HashMap::new(),
+            instances: HashMap::new(),
+            connections: HashMap::new(),
+            reconnectors: HashMap::new(),
+            channels: Channels::new(),
+            nicknames,
+            current_channel: None,
+            presence,
+            idle: IdleTracker::new(away_after),
+            receipts: Receipts::new(Duration::from_secs(10)),
+            outbox,
+            flushing: HashMap::new(),
+            history,
+            clock: LamportClock::new(),
+            node_id: node.identity.node_id(),
+            relay,
+            seen: Seen::new(10_000),
+            outgoing_files: HashMap::new(),
+            incoming_files: HashMap::new(),
+            expected_files: node.expected_files.clone(),
+            offers: Vec::new(),
+            download_dir: transfer::default_download_dir(),
+            composer: Composer::new(),
+            reorder: ReorderBuffer::new(Duration::from_millis(500)),
         }
     }
 
     fn save_registration(&mut self, registration: dnssd::Registration) {
-        self.service_registration = Some(registration);
+        self.registrations.push(registration);
+    }
+
+    fn add_peer(&mut self, node_id: NodeId, peer: Peer) -> bool {
+        self.channels
+            .set_peer_channels(node_id.clone(), peer.channels.clone());
+        self.peers.insert(node_id, peer).is_none()
+    }
+
+    fn drop_peer(&mut self, node_id: &NodeId) -> bool {
+        self.instances.remove(node_id);
+        self.reconnectors.remove(node_id);
+        self.connections.remove(node_id);
+        self.channels.drop_peer(node_id);
+        self.nicknames.remove(node_id);
+        self.peers.remove(node_id).is_some()
+    }
+
+    fn add_connection(&mut self, node_id: NodeId, sender: chat::Sender) {
+        // Our TXT record may be stale by the time the peer resolved it
+        for channel in self.channels.joined() {
+            let _ = sender.unbounded_send(Frame::Join {
+                channel: channel.clone(),
+            });
+        }
+        let _ = sender.unbounded_send(Frame::Presence(self.presence.clone()));
+        self.request_history(&sender, None);
+        for channel in self.channels.joined() {
+            self.request_history(&sender, Some(channel.clone()));
+        }
+        self.connections.insert(node_id.clone(), sender);
+        // Whatever was in flight over the last connection is lost with it
+        self.flushing.remove(&node_id);
+        self.flush_outbox(&node_id);
+        // Offer again whatever we were sending when we lost the peer; it picks up where it
+        // left off
+        let offers: Vec<Offer> = self
+            .outgoing_files
+            .values_mut()
+            .filter(|outgoing| outgoing.to == node_id)
+            .map(|outgoing| {
+                outgoing.pause();
+                outgoing.offer.clone()
+            })
+            .collect();
+        for offer in offers {
+            self.send_to(&node_id, Frame::FileOffer(offer));
+        }
+    }
+
+    /// Finds a peer by nickname, node id or service name.
+    fn find_peer(&self, name: &str) -> Option<&NodeId> {
+        if let Some(node_id) = self.nicknames.find(name) {
+            if self.peers.contains_key(node_id) {
+                return Some(node_id);
+            }
+        }
+        self.peers
+            .iter()
+            .find(|(node_id, peer)| node_id.as_str() == name || peer.servicename == name)
+            .map(|(node_id, _)| node_id)
+    }
+
+    fn broadcast(&self, frame: Frame) {
+        for sender in self.connections.values() {
+            let _ = sender.unbounded_send(frame.clone());
+        }
+    }
+
+    /// Sends `frame` over the connection to `node_id` alone. Returns `false` if we aren't
+    /// connected to it.
+    fn send_to(&self, node_id: &NodeId, frame: Frame) -> bool {
+        match self.connections.get(node_id) {
+            Some(sender) => sender.unbounded_send(frame).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Sends `message` to its recipient if it's private, otherwise to everyone in its channel,
+    /// or everyone we're connected to if it isn't in one, and tracks whether each of them gets
+    /// it.
+    fn send_message(&mut self, message: Message) {
+        // Relays may echo it back to us
+        self.seen.insert(&message.id);
+        self.record(&message);
+        if let Some(to) = message.to.clone() {
+            if !self.connections.contains_key(&to) {
+                self.queue_message(&to, message);
+                return;
+            }
+        }
+        let recipients: Vec<NodeId> = match (&message.to, &message.channel) {
+            (Some(to), _) => vec![to.clone()],
+            (None, Some(channel)) => self.channels.members(channel).cloned().collect(),
+            (None, None) => self.connections.keys().cloned().collect(),
+        };
+        let sent: Vec<(NodeId, bool)> = recipients
+            .into_iter()
+            .map(|node_id| {
+                let sent = self.send_to(&node_id, Frame::Message(message.clone()));
+                (node_id, sent)
+            })
+            .collect();
+        self.receipts.sent(&message, sent);
+    }
+
+    fn record(&mut self, message: &Message) {
+        if let Err(err) = self.history.record(message) {
+            say!("Error occurred recording history: {:?}", err);
+        }
+    }
+
+    /// Shows and records the messages a peer sent us from its history that we haven't seen.
+    /// Several peers may send the same ones, so they're matched up by id.
+    fn catch_up(&mut self, mut messages: Vec<chat::Received>) {
+        messages.sort_by(|a, b| ordering::cmp(&a.message, &b.message));
+        for chat::Received {
+            message, verified, ..
+        } in messages
+        {
+            // Only the author can vouch for a message that's been relayed
+            if !verified || self.history.contains(&message.id) {
+                continue;
+            }
+            if let Some(ref channel) = message.channel {
+                if !self.channels.is_joined(channel) {
+                    continue;
+                }
+            }
+            self.record(&message);
+            self.clock.observe(message.clock);
+            say!(
+                "[{}] {}",
+                format_timestamp(message.timestamp),
+                format_message(&message.nick, &message, verified)
+            );
+        }
+    }
+
+    /// Relays a message that came from `peer` on to our other connections, so that it reaches
+    /// nodes that can't reach its author. A private message only goes to its recipient, if
+    /// we're connected to it, and a channel message only to the channel's members.
+    fn forward(&self, peer: &NodeId, message: &Message) {
+        if message.ttl <= 1 {
+            return;
+        }
+        let mut message = message.clone();
+        message.ttl -= 1;
+        if let Some(ref to) = message.to {
+            if *to != self.node_id {
+                self.send_to(to, Frame::Message(message.clone()));
+            }
+            return;
+        }
+        let recipients: Vec<&NodeId> = match message.channel {
+            Some(ref channel) => self.channels.members(channel).collect(),
+            None => self.connections.keys().collect(),
+        };
+        for node_id in recipients {
+            if node_id != peer && *node_id != message.from {
+                self.send_to(node_id, Frame::Message(message.clone()));
+            }
+        }
+    }
+
+    /// Keeps a direct message for a peer we aren't connected to until it's back.
+    fn queue_message(&mut self, to: &NodeId, message: Message) {
+        let name = self.nicknames.display_name(to);
+        match self.outbox.push(message.clone()) {
+            Ok(true) => say!(
+                "{} is offline; the message will be delivered when they're back",
+                name
+            ),
+            Ok(false) => {
+                say!("Outbox is full");
+                self.receipts.sent(&message, vec![(to.clone(), false)]);
+            }
+            Err(err) => {
+                say!("Error occurred queueing message: {:?}", err);
+                self.receipts.sent(&message, vec![(to.clone(), false)]);
+            }
+        }
+    }
+
+    /// Sends `node_id` the next of the messages queued for it, keeping no more than
+    /// `OUTBOX_WINDOW` unacknowledged at once so as not to run into its rate limit. Each one
+    /// stays queued until it's acknowledged.
+    fn flush_outbox(&mut self, node_id: &NodeId) {
+        let sender = match self.connections.get(node_id) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let in_flight = self.flushing.entry(node_id.clone()).or_default();
+        let mut sent = Vec::new();
+        for message in self.outbox.queued_for(node_id) {
+            if in_flight.len() >= OUTBOX_WINDOW {
+                break;
+            }
+            if in_flight.insert(message.id.clone()) {
+                let ok = sender
+                    .unbounded_send(Frame::Message(message.clone()))
+                    .is_ok();
+                sent.push((message.clone(), ok));
+            }
+        }
+        for (message, ok) in sent {
+            self.receipts.sent(&message, vec![(node_id.clone(), ok)]);
+        }
     }
 
-    fn add_peer(&mut self, peer: Peer) -> bool {
-        self.peers.insert(peer)
+    /// Takes a queued message off the outbox once `peer` has acknowledged it, and sends the
+    /// next one.
+    fn delivered_queued(&mut self, peer: &NodeId, id: &str) {
+        let was_in_flight = match self.flushing.get_mut(peer) {
+            Some(in_flight) => in_flight.remove(id),
+            None => false,
+        };
+        if !was_in_flight {
+            return;
+        }
+        if let Err(err) = self.outbox.remove(id) {
+            say!("Error occurred updating outbox: {:?}", err);
+        }
+        self.flush_outbox(peer);
     }
 
-    fn drop_peer(&mut self, peer: &Peer) -> bool {
-        self.peers.remove(&peer)
+    fn join(&mut self, channel: String) {
+        if self.channels.join(&channel) {
+            self.broadcast(Frame::Join {
+                channel: channel.clone(),
+            });
+            self.advertise_channels();
+            // Catch up on what was said before we joined
+            for node_id in self.channels.members(&channel) {
+                if let Some(sender) = self.connections.get(node_id) {
+                    self.request_history(sender, Some(channel.clone()));
+                }
+            }
+        }
+        self.current_channel = Some(channel);
+    }
+
+    /// Asks a peer for the messages in `channel` that we haven't seen.
+    fn request_history(&self, sender: &chat::Sender, channel: Option<String>) {
+        let latest = self.history.latest(channel.as_deref());
+        let _ = sender.unbounded_send(Frame::HistoryRequest {
+            since: latest.map(|message| message.timestamp).unwrap_or(0),
+            after: latest.map(|message| message.id.clone()),
+            channel,
+        });
+    }
+
+    fn part(&mut self, channel: &str) -> bool {
+        if !self.channels.leave(channel) {
+            return false;
+        }
+        self.broadcast(Frame::Leave {
+            channel: channel.to_owned(),
+        });
+        self.advertise_channels();
+        if self.current_channel.as_deref() == Some(channel) {
+            self.current_channel = None;
+        }
+        true
+    }
+
+    /// Publishes the channels we're in, for peers that discover us later.
+    fn advertise_channels(&mut self) {
+        self.txt_record
+            .insert("ch", &channels::to_txt(self.channels.joined()));
+        self.update_txt_record();
+    }
+
+    /// Tells connected peers about our new presence, and publishes it for those that discover us
+    /// later.
+    fn set_presence(&mut self, presence: Presence) {
+        if presence == self.presence {
+            return;
+        }
+        say!("You are now {}", presence);
+        self.broadcast(Frame::Presence(presence.clone()));
+        self.txt_record.insert("ps", &presence.to_txt());
+        self.presence = presence;
+        self.update_txt_record();
+    }
+
+    fn update_txt_record(&self) {
+        for registration in &self.registrations {
+            if let Err(err) = registration.update_txt_record(&self.txt_record) {
+                say!("Error occurred updating TXT record: {:?}", err);
+            }
+        }
     }
 }
 
 fn register_service_task(
     state: Arc<Mutex<State>>,
+    scope: &dnssd::Scope,
+    node: &chat::Node,
 ) -> Result<impl Future<Item = (), Error = ()>, dnssd::Error> {
-    let f = dnssd::register_service()?
+    let txt_record = state.lock().unwrap().txt_record.clone();
+    let servicename = Arc::clone(&node.servicename);
+    let f = dnssd::register_service(scope, node.port, &txt_record)?
         .and_then(move |registration| {
+            // So that peers we connect to can check us against the key they pinned for it
+            *servicename.lock().unwrap() = Some(registration.service().name.clone());
             let mut guard = state.lock().unwrap();
             (*guard).save_registration(registration);
             Ok(())
         })
         .map_err(|err| {
-            println!("Error occurred registering service: {:?}", err);
+            say!("Error occurred registering service: {:?}", err);
         });
     Ok(f)
 }
 
+/// Starts keeping a connection open to a peer that's been discovered or configured.
+fn peer_found(
+    state: &Mutex<State>,
+    node: &chat::Node,
+    tx: &mpsc::UnboundedSender<chat::Event>,
+    node_id: NodeId,
+    peer: Peer,
+) {
+    // We also discover our own service, but there's no point talking to it
+    let mut guard = state.lock().unwrap();
+    // Seeing the peer again, on another interface or address, mustn't disturb our connection
+    if node_id != node.identity.node_id() && !guard.reconnectors.contains_key(&node_id) {
+        let (task, handle) = reconnect::maintain(peer.clone(), node, tx.clone());
+        tokio::spawn(task);
+        guard.reconnectors.insert(node_id.clone(), handle);
+        say!("Found {}", peer.servicename);
+    }
+    guard
+        .instances
+        .entry(node_id.clone())
+        .or_default()
+        .insert((peer.servicename.clone(), peer.interface));
+    (*guard).add_peer(node_id, peer);
+}
+
+/// Forgets one of the places a peer was found, and the peer itself if that was the last.
+fn peer_lost(state: &Mutex<State>, node: &chat::Node, node_id: NodeId, peer: Peer) {
+    let mut guard = state.lock().unwrap();
+    let instance = (peer.servicename.clone(), peer.interface);
+    let remaining = match guard.instances.get_mut(&node_id) {
+        Some(instances) => {
+            instances.remove(&instance);
+            instances.len()
+        }
+        None => 0,
+    };
+    if remaining > 0 {
+        return;
+    }
+    if node_id != node.identity.node_id() {
+        say!("Lost {}", peer.servicename);
+    }
+    (*guard).drop_peer(&node_id);
+}
+
+/// Connects to the peers in the config file, whether or not discovery finds them. Each is
+/// probed until it answers, then treated like a discovered peer that never goes away.
+fn static_peers_task(
+    state: Arc<Mutex<State>>,
+    node: chat::Node,
+    tx: mpsc::UnboundedSender<chat::Event>,
+    peers: &[String],
+) -> impl Future<Item = (), Error = ()> {
+    let mut tasks = Vec::new();
+    for name in peers {
+        let addr = match name.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) | Err(_) => {
+                say!("Can't find an address for {}", name);
+                continue;
+            }
+        };
+        let name = name.clone();
+        let probe = future::loop_fn(0, {
+            let node = node.clone();
+            move |failures| {
+                let delay = node.backoff.delay(failures + 1);
+                chat::probe(addr, name.clone(), &node).then(move |result| match result {
+                    Ok(peer) => Either::A(future::ok(Loop::Break(peer))),
+                    Err(_) => Either::B(
+                        Delay::new(Instant::now() + delay)
+                            .then(move |_| Ok(Loop::Continue(failures + 1))),
+                    ),
+                })
+            }
+        });
+        let state = Arc::clone(&state);
+        let node = node.clone();
+        let tx = tx.clone();
+        tasks.push(probe.map(move |peer: Peer| {
+            if let Some(node_id) = peer.node_id.clone() {
+                peer_found(&state, &node, &tx, node_id, peer);
+            }
+        }));
+    }
+    future::join_all(tasks).map(|_| ())
+}
+
 fn track_peers_task(
     state: Arc<Mutex<State>>,
+    scopes: &[dnssd::Scope],
+    node: chat::Node,
+    tx: mpsc::UnboundedSender<chat::Event>,
 ) -> Result<impl Future<Item = (), Error = ()>, dnssd::Error> {
-    let task = track_peers()?
+    let task = discover(scopes)?
         .for_each(move |peer_event| {
             let PeerEvent { peer, event } = peer_event;
-            match event {
-                NetworkEvent::Joined => {
-                    let mut guard = state.lock().unwrap();
-                    (*guard).add_peer(peer);
-                }
-                NetworkEvent::Dropped => {
-                    let mut guard = state.lock().unwrap();
-                    (*guard).drop_peer(&peer);
+            let node_id = match peer.node_id.clone() {
+                Some(node_id) => node_id,
+                None => {
+                    say!("Ignoring peer without a node id: {:?}", peer);
+                    return Ok(());
                 }
+            };
+            match event {
+                NetworkEvent::Joined => peer_found(&state, &node, &tx, node_id, peer),
+                NetworkEvent::Dropped => peer_lost(&state, &node, node_id, peer),
             }
-            println!("State: {:?}", state);
             Ok(())
         })
         .map_err(|err| {
-            println!("Error occurred tracking peers: {:?}", err);
+            say!("Error occurred tracking peers: {:?}", err);
             ()
         });
     Ok(task)
 }
 
-fn main() {
-    let state = Arc::new(Mutex::new(State::new()));
-    let (tx, rx): (
-        mpsc::UnboundedSender<(SocketAddr, Bytes)>,
-        mpsc::UnboundedReceiver<(SocketAddr, Bytes)>,
-    ) = mpsc::unbounded();
-    let log_connections_task = rx.for_each(|(addr, msg)| {
-        println!("Peer {:?} says: {:?}", addr, msg);
+fn send_direct(state: &mut State, node: &chat::Node, mut args: Args) -> Result<(), Usage> {
+    let name = args.required()?;
+    let text = args.rest_required()?;
+    // Peers that aren't around can still be written to, if we've met them before
+    let known = || {
+        node.known_peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(servicename, key)| (servicename.to_owned(), key.node_id()))
+            .find(|(servicename, node_id)| servicename == name || node_id.as_str() == name)
+            .map(|(_, node_id)| node_id)
+    };
+    let node_id = match state.find_peer(name).cloned().or_else(known) {
+        Some(node_id) => node_id,
+        None => {
+            say!("No such peer: {}", name);
+            return Ok(());
+        }
+    };
+    let message = Message::direct(
+        &node.identity,
+        node.nickname(),
+        node_id.clone(),
+        state.clock.tick(),
+        text.to_owned(),
+    );
+    state.send_message(message);
+    Ok(())
+}
+
+fn list_channels(state: &State) {
+    for (channel, peers) in state.channels.list() {
+        let marker = if state.channels.is_joined(channel) {
+            "*"
+        } else {
+            " "
+        };
+        say!("{} {} ({} peers)", marker, channel, peers);
+    }
+}
+
+fn change_nick(state: &mut State, node: &chat::Node, args: Args) -> Result<(), Usage> {
+    let nick = nicknames::normalize(args.rest()).ok_or(Usage)?;
+    *node.nickname.lock().unwrap() = nick.clone();
+    let node_id = node.identity.node_id();
+    state.nicknames.claim(node_id.clone(), nick.clone());
+    state.broadcast(Frame::Nick { nick: nick.clone() });
+    if !state.nicknames.owns_claim(&node_id) {
+        say!(
+            "{} is taken; you'll be shown as {}",
+            nick,
+            state.nicknames.display_name(&node_id)
+        );
+    }
+    Ok(())
+}
+
+/// `/send <peer> <path>` offers a file to a peer.
+fn send_file(state: &mut State, mut args: Args) -> Result<(), Usage> {
+    let name = args.required()?;
+    let path = args.rest_required()?;
+    let node_id = match state.find_peer(name) {
+        Some(node_id) => node_id.clone(),
+        None => {
+            say!("No such peer: {}", name);
+            return Ok(());
+        }
+    };
+    let outgoing = match Outgoing::new(Path::new(path), node_id.clone()) {
+        Ok(outgoing) => outgoing,
+        Err(err) => {
+            say!("Can't send {}: {}", path, err);
+            return Ok(());
+        }
+    };
+    if !state.send_to(&node_id, Frame::FileOffer(outgoing.offer.clone())) {
+        say!("Not connected to {}", name);
+        return Ok(());
+    }
+    say!(
+        "Offered {} ({} bytes) to {}",
+        outgoing.offer.name,
+        outgoing.offer.size,
+        name
+    );
+    state
+        .outgoing_files
+        .insert(outgoing.offer.id.clone(), outgoing);
+    Ok(())
+}
+
+/// `/accept [id]` and `/reject [id]` answer the offer whose id starts with `id`, or the oldest
+/// one.
+fn answer_offer(state: &mut State, accept: bool, args: &str) {
+    let position = state
+        .offers
+        .iter()
+        .position(|(_, offer)| offer.id.starts_with(args));
+    let (peer, offer) = match position {
+        Some(position) => state.offers.remove(position),
+        None => {
+            say!("No such offer");
+            return;
+        }
+    };
+    if !accept {
+        state.send_to(&peer, Frame::FileReject { id: offer.id });
+        return;
+    }
+    match Incoming::new(offer, peer.clone(), &state.download_dir) {
+        Ok(incoming) => accept_file(state, incoming),
+        Err(err) => say!("Can't receive file: {}", err),
+    }
+}
+
+/// Asks for the rest of a file, and keeps track of it as it arrives.
+fn accept_file(state: &mut State, incoming: Incoming) {
+    let id = incoming.offer.id.clone();
+    let from = incoming.from.clone();
+    let offset = incoming.received;
+    state
+        .expected_files
+        .lock()
+        .unwrap()
+        .insert((from.clone(), id.clone()));
+    state.incoming_files.insert(id.clone(), incoming);
+    state.send_to(
+        &from,
+        Frame::FileAccept {
+            id: id.clone(),
+            offset,
+        },
+    );
+    // There may be nothing left to send
+    finish_file(state, &id);
+}
+
+/// Checks and saves a file we've received all of, and tells the sender how it went.
+fn finish_file(state: &mut State, id: &str) {
+    let complete = state
+        .incoming_files
+        .get(id)
+        .is_some_and(|incoming| incoming.is_complete());
+    if !complete {
+        return;
+    }
+    let incoming = match state.incoming_files.remove(id) {
+        Some(incoming) => incoming,
+        None => return,
+    };
+    state
+        .expected_files
+        .lock()
+        .unwrap()
+        .remove(&(incoming.from.clone(), id.to_owned()));
+    let ok = match incoming.finish() {
+        Ok(Some(path)) => {
+            say!("Received {}", path.display());
+            true
+        }
+        Ok(None) => {
+            say!(
+                "{} didn't match its hash; discarded it",
+                incoming.offer.name
+            );
+            false
+        }
+        Err(err) => {
+            say!("Error occurred saving {}: {}", incoming.offer.name, err);
+            false
+        }
+    };
+    state.send_to(
+        &incoming.from,
+        Frame::FileDone {
+            id: id.to_owned(),
+            ok,
+        },
+    );
+}
+
+/// Prints progress each time a transfer passes another tenth of the file.
+fn print_progress(name: &str, before: u64, after: u64, size: u64) {
+    if size == 0 {
+        return;
+    }
+    let (before, after) = (before * 10 / size, after * 10 / size);
+    if after > before {
+        say!("{}: {}%", name, after * 10);
+    }
+}
+
+/// Sends as much of a file as the receiver has room for.
+fn send_chunks(state: &mut State, id: &str) {
+    let (to, chunks) = match state.outgoing_files.get_mut(id) {
+        Some(outgoing) => (outgoing.to.clone(), outgoing.next_chunks()),
+        None => return,
+    };
+    match chunks {
+        Ok(chunks) => {
+            for chunk in chunks {
+                state.send_to(&to, Frame::FileChunk(chunk));
+            }
+        }
+        Err(err) => {
+            say!("Error occurred reading file: {}", err);
+            state.outgoing_files.remove(id);
+        }
+    }
+}
+
+fn handle_file(state: &mut State, peer: NodeId, signal: Signal) {
+    match signal {
+        Signal::Offer(offer) => {
+            if !offer.has_valid_id() {
+                say!(
+                    "Ignoring a malformed file offer from {}",
+                    state.nicknames.display_name(&peer)
+                );
+                return;
+            }
+            let resuming = state
+                .incoming_files
+                .get(&offer.id)
+                .is_some_and(|incoming| incoming.from == peer);
+            if resuming {
+                if let Some(incoming) = state.incoming_files.remove(&offer.id) {
+                    accept_file(state, incoming);
+                }
+                return;
+            }
+            let short_id = offer.id[..8].to_owned();
+            say!(
+                "{} offers {} ({} bytes). /accept {} or /reject {}",
+                state.nicknames.display_name(&peer),
+                offer.name,
+                offer.size,
+                short_id,
+                short_id
+            );
+            state.offers.push((peer, offer));
+        }
+        Signal::Accept { id, offset } => {
+            match state.outgoing_files.get_mut(&id) {
+                Some(outgoing) if outgoing.to == peer => outgoing.accepted(offset),
+                _ => return,
+            }
+            send_chunks(state, &id);
+        }
+        Signal::Reject { id } => {
+            if let Some(outgoing) = state.outgoing_files.remove(&id) {
+                say!(
+                    "{} turned down {}",
+                    state.nicknames.display_name(&peer),
+                    outgoing.offer.name
+                );
+            }
+        }
+        Signal::Progress { id, received } => {
+            match state.outgoing_files.get_mut(&id) {
+                Some(outgoing) if outgoing.to == peer => {
+                    let before = outgoing.acked;
+                    outgoing.progress(received);
+                    print_progress(
+                        &outgoing.offer.name,
+                        before,
+                        outgoing.acked,
+                        outgoing.offer.size,
+                    );
+                }
+                _ => return,
+            }
+            send_chunks(state, &id);
+        }
+        Signal::Chunk(chunk) => {
+            let received = match state.incoming_files.get_mut(&chunk.id) {
+                Some(incoming) if incoming.from == peer => {
+                    let before = incoming.received;
+                    match incoming.write(&chunk) {
+                        Ok(true) => {}
+                        Ok(false) => return,
+                        Err(err) => {
+                            say!("Error occurred writing {}: {}", incoming.offer.name, err);
+                            return;
+                        }
+                    }
+                    print_progress(
+                        &incoming.offer.name,
+                        before,
+                        incoming.received,
+                        incoming.offer.size,
+                    );
+                    // Report a few times a window, so as not to run into the sender's rate
+                    // limit
+                    let step = transfer::WINDOW / 4;
+                    if before / step == incoming.received / step && !incoming.is_complete() {
+                        return;
+                    }
+                    incoming.received
+                }
+                _ => return,
+            };
+            state.send_to(
+                &peer,
+                Frame::FileProgress {
+                    id: chunk.id.clone(),
+                    received,
+                },
+            );
+            finish_file(state, &chunk.id);
+        }
+        Signal::Done { id, ok } => {
+            if let Some(outgoing) = state.outgoing_files.remove(&id) {
+                if ok {
+                    say!("Sent {}", outgoing.offer.name);
+                } else {
+                    say!("{} arrived corrupted", outgoing.offer.name);
+                }
+            }
+        }
+    }
+}
+
+/// The text a presence command was given, if any.
+fn optional_text(args: Args) -> Option<String> {
+    if args.is_empty() {
+        None
+    } else {
+        Some(args.rest().to_owned())
+    }
+}
+
+/// Sets the presence chosen with `/away`, `/busy`, `/back` or `/status`.
+fn change_presence(state: &mut State, presence: Presence) {
+    state.idle.set_manually();
+    state.set_presence(presence);
+}
+
+fn list_peers(state: &State) {
+    for (node_id, peer) in &state.peers {
+        say!(
+            "{} ({}) {}",
+            state.nicknames.display_name(node_id),
+            peer.servicename,
+            peer.presence
+        );
+    }
+}
+
+/// Sends `text` to the current channel.
+fn send_text(state: &mut State, node: &chat::Node, text: String) {
+    let clock = state.clock.tick();
+    let message = match state.current_channel.clone() {
+        Some(channel) => Message::to_channel(&node.identity, node.nickname(), channel, clock, text),
+        None => Message::new(&node.identity, node.nickname(), clock, text),
+    };
+    state.send_message(message);
+}
+
+/// What commands act on.
+#[derive(Clone)]
+struct Client {
+    state: Arc<Mutex<State>>,
+    node: chat::Node,
+    events: mpsc::UnboundedSender<chat::Event>,
+    commands: Arc<Commands<Client>>,
+}
+
+/// `/connect <peer>` connects to a discovered peer right away, rather than waiting out the
+/// backoff.
+fn connect_peer(
+    state: &mut State,
+    node: &chat::Node,
+    events: &mpsc::UnboundedSender<chat::Event>,
+    mut args: Args,
+) -> Result<(), Usage> {
+    let name = args.required()?;
+    let node_id = match state.find_peer(name) {
+        Some(node_id) if *node_id != state.node_id => node_id.clone(),
+        _ => {
+            say!("No such peer: {}", name);
+            return Ok(());
+        }
+    };
+    if state.connections.contains_key(&node_id) {
+        say!("Already connected to {}", name);
+        return Ok(());
+    }
+    let (task, handle) = reconnect::maintain(state.peers[&node_id].clone(), node, events.clone());
+    tokio::spawn(task);
+    // Replacing the handle stops the attempt that's backing off
+    state.reconnectors.insert(node_id, handle);
+    say!("Connecting to {}", name);
+    Ok(())
+}
+
+fn show_help(commands: &Commands<Client>, mut args: Args) -> Result<(), Usage> {
+    if let Some(name) = args.word() {
+        match commands.get(name) {
+            Some(command) => say!("{}\n    {}", command.usage, command.help),
+            None => say!("No such command: {}", name),
+        }
+        return Ok(());
+    }
+    let width = commands.iter().map(|command| command.usage.len()).max();
+    for command in commands.iter() {
+        say!(
+            "{:width$}  {}",
+            command.usage,
+            command.help,
+            width = width.unwrap_or(0)
+        );
+    }
+    Ok(())
+}
+
+/// The commands the client understands.
+fn commands() -> Commands<Client> {
+    let mut commands = Commands::new();
+    commands.register(
+        "help",
+        "/help [command]",
+        "Lists the commands, or explains one",
+        |client: &mut Client, args| show_help(&client.commands, args),
+    );
+    commands.register(
+        "msg",
+        "/msg <peer> <text>",
+        "Sends a private message",
+        |client, args| {
+            let mut state = client.state.lock().unwrap();
+            send_direct(&mut state, &client.node, args)
+        },
+    );
+    commands.register(
+        "compose",
+        "/compose",
+        "Writes a message over several lines",
+        |client, _| {
+            client.state.lock().unwrap().composer.start();
+            say!("Composing a message; end it with a line holding only a '.', or /cancel");
+            Ok(())
+        },
+    );
+    commands.register(
+        "nick",
+        "/nick <nickname>",
+        "Changes your nickname",
+        |client, args| {
+            let mut state = client.state.lock().unwrap();
+            change_nick(&mut state, &client.node, args)
+        },
+    );
+    commands.register(
+        "join",
+        "/join <#channel>",
+        "Joins a channel and talks in it",
+        |client, args| {
+            let channel = channels::normalize(args.rest()).ok_or(Usage)?;
+            client.state.lock().unwrap().join(channel);
+            Ok(())
+        },
+    );
+    commands.register(
+        "part",
+        "/part [#channel]",
+        "Leaves a channel, the current one by default",
+        |client, args| {
+            let mut state = client.state.lock().unwrap();
+            let channel = if args.is_empty() {
+                state.current_channel.clone()
+            } else {
+                channels::normalize(args.rest())
+            };
+            match channel {
+                Some(ref channel) if state.part(channel) => {}
+                Some(channel) => say!("Not in {}", channel),
+                None => return Err(Usage),
+            }
+            Ok(())
+        },
+    );
+    commands.register("list", "/list", "Lists the channels in use", |client, _| {
+        list_channels(&client.state.lock().unwrap());
         Ok(())
     });
-    let registrations_task = register_service_task(Arc::clone(&state))
+    commands.register(
+        "who",
+        "/who",
+        "Lists the peers on the network",
+        |client, _| {
+            list_peers(&client.state.lock().unwrap());
+            Ok(())
+        },
+    );
+    commands.register(
+        "connect",
+        "/connect <peer>",
+        "Connects to a peer now instead of waiting to retry",
+        |client, args| {
+            let mut state = client.state.lock().unwrap();
+            connect_peer(&mut state, &client.node, &client.events, args)
+        },
+    );
+    commands.register(
+        "away",
+        "/away [text]",
+        "Tells everyone you're away",
+        |client, args| {
+            let presence = Presence::new(Status::Away, optional_text(args));
+            change_presence(&mut client.state.lock().unwrap(), presence);
+            Ok(())
+        },
+    );
+    commands.register(
+        "busy",
+        "/busy [text]",
+        "Tells everyone you're busy",
+        |client, args| {
+            let presence = Presence::new(Status::Busy, optional_text(args));
+            change_presence(&mut client.state.lock().unwrap(), presence);
+            Ok(())
+        },
+    );
+    commands.register(
+        "back",
+        "/back",
+        "Tells everyone you're back",
+        |client, _| {
+            change_presence(&mut client.state.lock().unwrap(), Presence::online());
+            Ok(())
+        },
+    );
+    commands.register(
+        "status",
+        "/status <online|away|busy|offline> [text]",
+        "Sets your presence",
+        |client, mut args| {
+            let status = args.word().and_then(Status::parse).ok_or(Usage)?;
+            let presence = Presence::new(status, optional_text(args));
+            change_presence(&mut client.state.lock().unwrap(), presence);
+            Ok(())
+        },
+    );
+    commands.register(
+        "send",
+        "/send <peer> <path>",
+        "Offers a file to a peer",
+        |client, args| send_file(&mut client.state.lock().unwrap(), args),
+    );
+    commands.register(
+        "accept",
+        "/accept [id]",
+        "Accepts a file, the oldest offered by default",
+        |client, args| {
+            answer_offer(&mut client.state.lock().unwrap(), true, args.rest());
+            Ok(())
+        },
+    );
+    commands.register(
+        "reject",
+        "/reject [id]",
+        "Turns down a file, the oldest offered by default",
+        |client, args| {
+            answer_offer(&mut client.state.lock().unwrap(), false, args.rest());
+            Ok(())
+        },
+    );
+    commands.register("quit", "/quit", "Leaves the chat", |_, _| quit());
+    commands
+}
+
+/// Handles a line typed by the user. Commands start with a `/`; anything else is sent to the
+/// current channel.
+fn handle_input(client: &mut Client, line: String) {
+    let line = {
+        let mut guard = client.state.lock().unwrap();
+        if let Some(presence) = guard.idle.input() {
+            (*guard).set_presence(presence);
+        }
+        match guard.composer.feed(line) {
+            Input::Line(ref line) if commands::is_command(line) => line.clone(),
+            Input::Line(line) => {
+                if !line.is_empty() {
+                    send_text(&mut guard, &client.node, line);
+                }
+                return;
+            }
+            Input::Message(text) => {
+                send_text(&mut guard, &client.node, text);
+                return;
+            }
+            Input::Started => {
+                say!("Composing a code block; end it with ``` or /cancel");
+                return;
+            }
+            Input::Pending => return,
+            Input::Cancelled => {
+                say!("Discarded message");
+                return;
+            }
+        }
+    };
+    // Commands lock the state themselves
+    let commands = Arc::clone(&client.commands);
+    if let Err(err) = commands.dispatch(client, &line) {
+        say!("{}", err);
+    }
+}
+
+fn key_change_warning(servicename: &str, pinned: &NodeId, actual: &NodeId) -> String {
+    format!(
+        "WARNING: {:?} presented identity {} but was previously known as {}. \
+         Someone may be impersonating it. If its key legitimately changed, re-trust it.",
+        servicename, actual, pinned
+    )
+}
+
+fn handle_event(
+    state: &Mutex<State>,
+    node: &chat::Node,
+    tx: &mpsc::UnboundedSender<chat::Event>,
+    event: chat::Event,
+) {
+    let mut guard = state.lock().unwrap();
+    let received = match event {
+        chat::Event::Message(received) => received,
+        chat::Event::Join { peer, channel } => {
+            guard.channels.peer_joined(peer, channel);
+            return;
+        }
+        chat::Event::Leave { peer, channel } => {
+            guard.channels.peer_left(&peer, &channel);
+            return;
+        }
+        chat::Event::Nick { peer, nick } => {
+            if let Some(nick) = nicknames::normalize(&nick) {
+                guard.nicknames.claim(peer, nick);
+            }
+            return;
+        }
+        chat::Event::Dropped { peer } => {
+            say!("{} stopped responding", guard.nicknames.display_name(&peer));
+            // Stop sending it anything until a fresh connection is up, which shows it as
+            // disconnected too, and start over on that connection, whichever one went quiet
+            guard.connections.remove(&peer);
+            if let Some(found) = guard.peers.get(&peer).cloned() {
+                if guard.reconnectors.contains_key(&peer) {
+                    let (task, handle) = reconnect::maintain(found, node, tx.clone());
+                    tokio::spawn(task);
+                    // Replacing the handle stops the old reconnector
+                    guard.reconnectors.insert(peer, handle);
+                }
+            }
+            return;
+        }
+        chat::Event::HistoryRequest {
+            peer,
+            channel,
+            since,
+            after,
+            reply,
+        } => {
+            // A channel's backlog is only for those in it
+            if let Some(ref channel) = channel {
+                if !guard.channels.is_member(&peer, channel) {
+                    return;
+                }
+            }
+            let messages = guard
+                .history
+                .since(
+                    channel.as_deref(),
+                    since,
+                    after.as_deref(),
+                    100,
+                    MAX_HISTORY_BYTES,
+                )
+                .into_iter()
+                .cloned()
+                .collect();
+            let _ = reply.unbounded_send(Frame::History { messages });
+            return;
+        }
+        chat::Event::History { messages, .. } => {
+            (*guard).catch_up(messages);
+            return;
+        }
+        chat::Event::File { peer, signal } => {
+            handle_file(&mut guard, peer, signal);
+            return;
+        }
+        chat::Event::Ack { peer, id } => {
+            guard.receipts.acked(&id, &peer);
+            guard.delivered_queued(&peer, &id);
+            return;
+        }
+        chat::Event::RateLimited { peer } => {
+            let name = guard.nicknames.display_name(&peer);
+            say!("{} dropped a message from us: we're sending too fast", name);
+            return;
+        }
+        chat::Event::Connection { peer, state } => {
+            let name = guard.nicknames.display_name(&peer);
+            match state {
+                // Discovery may have dropped the peer while we were connecting
+                ConnectionState::Connected(sender) if guard.peers.contains_key(&peer) => {
+                    say!("Connected to {}", name);
+                    (*guard).add_connection(peer, sender);
+                }
+                ConnectionState::Connected(_) => {}
+                ConnectionState::Connecting => {
+                    guard.connections.remove(&peer);
+                }
+                ConnectionState::BackingOff(delay) => {
+                    say!("Couldn't reach {}; trying again in {:?}", name, delay);
+                    guard.connections.remove(&peer);
+                }
+            }
+            return;
+        }
+        chat::Event::KeyChanged {
+            servicename,
+            pinned,
+            actual,
+            refused,
+        } => {
+            say!("{}", key_change_warning(&servicename, &pinned, &actual));
+            if refused {
+                say!("Refused the connection with {:?}", servicename);
+            }
+            return;
+        }
+        chat::Event::Warning(text) => {
+            say!("{}", text);
+            return;
+        }
+        chat::Event::Presence { peer, presence } => {
+            let name = guard.nicknames.display_name(&peer);
+            if let Some(known) = guard.peers.get_mut(&peer) {
+                known.presence = presence.clone();
+            }
+            say!("{} is now {}", name, presence);
+            return;
+        }
+    };
+    let chat::Received {
+        peer,
+        message,
+        verified,
+        ..
+    } = received;
+    if !guard.seen.insert(&message.id) {
+        return;
+    }
+    // Vouching for forgeries would only help them spread
+    if guard.relay && verified {
+        guard.forward(&peer, &message);
+    }
+    if message.to.as_ref().is_some_and(|to| *to != guard.node_id) {
+        return;
+    }
+    // A verified message is the latest word on its author's nickname
+    if verified {
+        if let Some(nick) = nicknames::normalize(&message.nick) {
+            guard.nicknames.claim(message.from.clone(), nick);
+        }
+    }
+    if let Some(ref channel) = message.channel {
+        // We may still be routed messages for channels we've just left
+        if !guard.channels.is_joined(channel) {
+            return;
+        }
+    }
+    // We may have already caught up on it from someone's history
+    if guard.history.contains(&message.id) {
+        return;
+    }
+    (*guard).record(&message);
+    // Anyone can claim any clock, so only the author's word moves ours along
+    if verified {
+        guard.clock.observe(message.clock);
+    }
+    guard.reorder.push(message, Instant::now());
+}
+
+/// Shows received messages once the reorder buffer has put them in order.
+fn display_task(state: Arc<Mutex<State>>, hooks: Hooks) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_millis(100))
+        .for_each(move |now| {
+            let mut guard = state.lock().unwrap();
+            for message in guard.reorder.pop_ready(now) {
+                let verified = message.verify();
+                let name = if verified {
+                    guard.nicknames.display_name(&message.from)
+                } else {
+                    // Don't let an unverified message borrow someone else's name
+                    format!("{}?", message.nick)
+                };
+                say!("{}", format_message(&name, &message, verified));
+                run_hooks(&hooks, &name, &message);
+            }
+            Ok(())
+        })
+        .map_err(|err| {
+            say!("Error occurred displaying messages: {:?}", err);
+        })
+}
+
+/// Runs the hooks configured for `message` in the background.
+fn run_hooks(hooks: &Hooks, name: &str, message: &Message) {
+    let private = if message.is_private() {
+        hooks.private.as_ref()
+    } else {
+        None
+    };
+    for hook in hooks.message.iter().chain(private) {
+        let child = process::Command::new("sh")
+            .arg("-c")
+            .arg(hook)
+            .env("LOCALCHAT_FROM", name)
+            .env(
+                "LOCALCHAT_CHANNEL",
+                message.channel.as_deref().unwrap_or(""),
+            )
+            .env("LOCALCHAT_BODY", &message.body)
+            // Whatever it prints would only get in the way
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(err) => say!("Error occurred running hook {:?}: {}", hook, err),
+        }
+    }
+}
+
+fn format_message(name: &str, message: &Message, verified: bool) -> String {
+    let mut markers = String::new();
+    if let Some(ref channel) = message.channel {
+        markers.push_str(&format!(" in {}", channel));
+    }
+    if message.is_private() {
+        markers.push_str(" (private)");
+    }
+    if !verified {
+        markers.push_str(" (unverified)");
+    }
+    // Indent the rest of a multi-line message so it's clear where it ends
+    let body = message.body.replace("\r\n", "\n").replace('\n', "\n    ");
+    format!("{}{}: {}", name, markers, body)
+}
+
+fn input_task(mut client: Client) -> impl Future<Item = (), Error = ()> {
+    FramedRead::new(tokio::io::stdin(), LinesCodec::new())
+        .for_each(move |line| {
+            handle_input(&mut client, line);
+            Ok(())
+        })
+        .map_err(|err| {
+            say!("Error occurred reading input: {:?}", err);
+        })
+}
+
+/// Reads keys in the full-screen interface, handing lines to `handle_input` once entered.
+fn screen_input_task(mut client: Client) -> impl Future<Item = (), Error = ()> {
+    let mut parser = KeyParser::new();
+    FramedRead::new(tokio::io::stdin(), BytesCodec::new())
+        .for_each(move |bytes| {
+            for key in parser.parse(&bytes) {
+                let line = {
+                    let mut ui = UI.lock().unwrap();
+                    let ui = match *ui {
+                        Some(ref mut ui) => ui,
+                        None => return Ok(()),
+                    };
+                    let (_, height) = Terminal::size();
+                    let page = height.saturating_sub(2).max(2) / 2;
+                    let line = match key {
+                        Key::Ctrl('c') => None,
+                        Key::Ctrl('d') if ui.screen.editor.text().is_empty() => None,
+                        Key::PageUp => {
+                            ui.screen.scroll_up(page);
+                            Some(None)
+                        }
+                        Key::PageDown => {
+                            ui.screen.scroll_down(page);
+                            Some(None)
+                        }
+                        // Only complete at the end of the line, where the word being typed is
+                        Key::Tab if ui.screen.editor.at_end() => {
+                            let text = ui.screen.editor.text();
+                            if let Some(completed) = client.commands.complete(&text, &ui.names) {
+                                ui.screen.editor.set_text(&completed);
+                            }
+                            Some(None)
+                        }
+                        key => Some(ui.screen.editor.handle_key(key)),
+                    };
+                    ui.draw();
+                    line
+                };
+                match line {
+                    None => quit(),
+                    Some(Some(line)) => {
+                        // Stand in for the terminal's echo, as in `--plain` mode
+                        say!("> {}", line);
+                        handle_input(&mut client, line);
+                    }
+                    Some(None) => {}
+                }
+            }
+            Ok(())
+        })
+        .map_err(|err| {
+            say!("Error occurred reading input: {:?}", err);
+        })
+}
+
+/// Keeps the peer list and status bar up to date.
+fn screen_task(state: Arc<Mutex<State>>, node: chat::Node) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_millis(500))
+        .for_each(move |_| {
+            let (sidebar, status, names) = {
+                let guard = state.lock().unwrap();
+                let mut peers: Vec<(String, &Peer, bool)> = guard
+                    .peers
+                    .iter()
+                    .filter(|&(node_id, _)| *node_id != guard.node_id)
+                    .map(|(node_id, peer)| {
+                        let connected = guard.connections.contains_key(node_id);
+                        (guard.nicknames.display_name(node_id), peer, connected)
+                    })
+                    .collect();
+                peers.sort_by(|a, b| a.0.cmp(&b.0));
+                let names = peers.iter().map(|(name, _, _)| name.clone()).collect();
+                let mut sidebar = vec![format!("Peers ({})", peers.len())];
+                for (name, peer, connected) in peers {
+                    let marker = if connected { '*' } else { ' ' };
+                    sidebar.push(format!("{} {} [{}]", marker, name, peer.presence.status));
+                }
+                let discovery = if !guard.registrations.is_empty() {
+                    "advertised"
+                } else {
+                    "registering"
+                };
+                let status = format!(
+                    " {} | {} | {} | {} connected | {}",
+                    node.nickname(),
+                    guard.presence,
+                    guard.current_channel.as_deref().unwrap_or("everyone"),
+                    guard.connections.len(),
+                    discovery
+                );
+                (sidebar, status, names)
+            };
+            if let Some(ref mut ui) = *UI.lock().unwrap() {
+                ui.names = names;
+                ui.screen.set_sidebar(sidebar);
+                ui.screen.set_status(status);
+                ui.draw();
+            }
+            Ok(())
+        })
+        .map_err(|err| {
+            say!("Error occurred updating the screen: {:?}", err);
+        })
+}
+
+/// Checks whether the user has gone idle every few seconds.
+fn idle_task(state: Arc<Mutex<State>>) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_secs(5))
+        .for_each(move |_| {
+            let mut guard = state.lock().unwrap();
+            let current = guard.presence.clone();
+            if let Some(presence) = guard.idle.check(&current) {
+                (*guard).set_presence(presence);
+            }
+            Ok(())
+        })
+        .map_err(|err| {
+            say!("Error occurred checking for idleness: {:?}", err);
+        })
+}
+
+/// Reports messages that didn't reach everyone they were sent to.
+fn receipts_task(state: Arc<Mutex<State>>) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_secs(1))
+        .for_each(move |_| {
+            let mut guard = state.lock().unwrap();
+            for undelivered in guard.receipts.expire() {
+                // A queued message is still queued: send it again rather than give up on it
+                if guard.outbox.contains(&undelivered.message.id) {
+                    for node_id in &undelivered.recipients {
+                        if let Some(in_flight) = guard.flushing.get_mut(node_id) {
+                            in_flight.remove(&undelivered.message.id);
+                        }
+                        guard.flush_outbox(node_id);
+                    }
+                    continue;
+                }
+                let names: Vec<String> = undelivered
+                    .recipients
+                    .iter()
+                    .map(|node_id| guard.nicknames.display_name(node_id))
+                    .collect();
+                let names = if names.is_empty() {
+                    "anyone".to_owned()
+                } else {
+                    names.join(", ")
+                };
+                say!("(undelivered to {}) {}", names, undelivered.message.body);
+            }
+            Ok(())
+        })
+        .map_err(|err| {
+            say!("Error occurred checking receipts: {:?}", err);
+        })
+}
+
+/// `localchat known-peers` lists the pinned identities.
+fn known_peers_command(known_peers: &KnownPeers) {
+    for (servicename, key) in known_peers.iter() {
+        println!("{}\t{}", key.node_id(), servicename);
+    }
+}
+
+/// `localchat trust <service name>` forgets a pinned identity, so that the key the peer presents
+/// next is trusted.
+fn trust_command(known_peers: &mut KnownPeers, servicename: &str) {
+    match known_peers.forget(servicename) {
+        Ok(true) => println!("Will trust the next key {:?} presents", servicename),
+        Ok(false) => println!("{:?} isn't pinned to a key", servicename),
+        Err(err) => println!("Error occurred updating known peers: {:?}", err),
+    }
+}
+
+/// Formats milliseconds since the Unix epoch as a UTC date and time.
+fn format_timestamp(timestamp: u64) -> String {
+    let secs = timestamp / 1000;
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// `localchat history [count]` shows the last `count` messages.
+fn history_command(config: &Config, count: usize) {
+    let history = open_history(config);
+    for message in history.page(None, count).unwrap_or_default() {
+        println!(
+            "[{}] {}",
+            format_timestamp(message.timestamp),
+            format_message(&message.nick, message, message.verify())
+        );
+    }
+}
+
+/// `localchat peers` browses for peers for `wait`, then lists the ones still around.
+fn peers_command(config: &Config, wait: Duration) {
+    if config.discovery == Discovery::None {
+        println!("Can't look for peers with discovery off");
+        process::exit(1);
+    }
+    let identity_path = Identity::default_path().expect("No config directory to keep identity in");
+    let own_id = Identity::load_or_generate(&identity_path)
+        .unwrap()
+        .node_id();
+    let peers = Arc::new(Mutex::new(BTreeMap::new()));
+    let browse_task = discover(&scopes(config))
+        .unwrap()
+        .for_each({
+            let peers = Arc::clone(&peers);
+            move |PeerEvent { peer, event }| {
+                // We may be running in the background; that's not a peer
+                if peer.node_id.as_ref() == Some(&own_id) {
+                    return Ok(());
+                }
+                let mut peers = peers.lock().unwrap();
+                match event {
+                    NetworkEvent::Joined => peers.insert(peer.servicename.clone(), peer),
+                    NetworkEvent::Dropped => peers.remove(&peer.servicename),
+                };
+                Ok(())
+            }
+        })
+        .map_err(|err| println!("Error occurred looking for peers: {:?}", err));
+    let timeout = Delay::new(Instant::now() + wait).map_err(|_| ());
+    tokio::run(browse_task.select2(timeout).then(|_| Ok(())));
+    let peers = peers.lock().unwrap();
+    if peers.is_empty() {
+        println!("No peers found");
+    }
+    for peer in peers.values() {
+        let node_id = peer.node_id.as_ref().map(NodeId::as_str).unwrap_or("?");
+        println!(
+            "{}\t{}\t{}\t{}",
+            peer.servicename, peer.socket_addr, peer.presence, node_id
+        );
+    }
+}
+
+/// `localchat send <peer> <message>` connects to the peers it finds until one goes by `name`,
+/// sends it `text` as a private message, and waits for it to arrive.
+fn send_command(config: &Config, name: String, text: String) {
+    if config.discovery == Discovery::None {
+        println!("Can't look for {} with discovery off", name);
+        process::exit(1);
+    }
+    let node = make_node(config);
+    let (tx, rx) = mpsc::unbounded();
+    let peers = Arc::new(Mutex::new(HashMap::new()));
+    let connect_task = discover(&scopes(config))
         .unwrap()
-        .and_then(move |_| track_peers_task(Arc::clone(&state)).unwrap());
-    tokio::run(lazy(|| {
-        tokio::spawn(chat::server(tx).join(log_connections_task).map(|_| ()));
-        tokio::spawn(registrations_task);
+        .for_each({
+            let node = node.clone();
+            let peers = Arc::clone(&peers);
+            move |PeerEvent { peer, event }| {
+                let node_id = match (event, peer.node_id.clone()) {
+                    (NetworkEvent::Joined, Some(node_id)) if node_id != node.identity.node_id() => {
+                        node_id
+                    }
+                    _ => return Ok(()),
+                };
+                peers
+                    .lock()
+                    .unwrap()
+                    .insert(node_id.clone(), peer.servicename.clone());
+                let tx = tx.clone();
+                // Nicknames only come with the handshake, so connect to whoever might be `name`
+                let connect = chat::connect(peer, &node, tx.clone()).then(move |result| {
+                    if let Ok((sender, connection)) = result {
+                        tokio::spawn(connection);
+                        let _ = tx.unbounded_send(chat::Event::Connection {
+                            peer: node_id,
+                            state: ConnectionState::Connected(sender),
+                        });
+                    }
+                    Ok(())
+                });
+                tokio::spawn(connect);
+                Ok(())
+            }
+        })
+        .map_err(|err| println!("Error occurred looking for peers: {:?}", err));
+    let mut history = open_history(config);
+    let mut nicks = HashMap::new();
+    let mut senders = HashMap::new();
+    let mut sent = None;
+    let deliver_task = rx.for_each({
+        let name = name.clone();
+        move |event| {
+            match event {
+                chat::Event::Nick { peer, nick } => {
+                    nicks.insert(peer, nick);
+                }
+                chat::Event::Connection {
+                    peer,
+                    state: ConnectionState::Connected(sender),
+                } => {
+                    senders.insert(peer, sender);
+                }
+                chat::Event::Ack { id, .. } if sent.as_ref() == Some(&id) => {
+                    println!("Delivered to {}", name);
+                    process::exit(0);
+                }
+                chat::Event::KeyChanged {
+                    servicename,
+                    pinned,
+                    actual,
+                    ..
+                } => println!("{}", key_change_warning(&servicename, &pinned, &actual)),
+                _ => {}
+            }
+            if sent.is_some() {
+                return Ok(());
+            }
+            let peers = peers.lock().unwrap();
+            let found = senders.iter().find(|&(node_id, _)| {
+                node_id.as_str() == name
+                    || nicks.get(node_id) == Some(&name)
+                    || peers.get(node_id) == Some(&name)
+            });
+            if let Some((node_id, sender)) = found {
+                let clock = LamportClock::new().tick();
+                let message = Message::direct(
+                    &node.identity,
+                    node.nickname(),
+                    node_id.clone(),
+                    clock,
+                    text.clone(),
+                );
+                if let Err(err) = history.record(&message) {
+                    println!("Error occurred recording history: {:?}", err);
+                }
+                sent = Some(message.id.clone());
+                let _ = sender.unbounded_send(Frame::Message(message));
+            }
+            Ok(())
+        }
+    });
+    let timeout = Delay::new(Instant::now() + SEND_TIMEOUT).then(move |_| -> Result<(), ()> {
+        println!("Couldn't deliver to {}", name);
+        process::exit(1);
+    });
+    tokio::run(lazy(move || {
+        tokio::spawn(connect_task);
+        tokio::spawn(deliver_task);
+        tokio::spawn(timeout);
         Ok(())
     }));
 }
+
+/// `$LOCALCHAT_NICK`, else the login name, else something to go by until `/nick`.
+fn default_nickname() -> String {
+    env::var("LOCALCHAT_NICK")
+        .or_else(|_| env::var("USER"))
+        .ok()
+        .and_then(|nick| nicknames::normalize(&nick))
+        .unwrap_or_else(|| "anonymous".to_owned())
+}
+
+/// `localchat config` prints the configuration in effect, defaults filled in, in the config
+/// file's format.
+fn config_command(path: &Path, mut config: Config) {
+    if config.nickname.is_none() {
+        config.nickname = Some(default_nickname());
+    }
+    config.history_path = Some(history_path(&config));
+    println!("# {}", path.display());
+    print!("{}", config);
+}
+
+/// Mentions the lines of `path` that couldn't be read, if there were any.
+fn report_skipped(path: &Path, skipped: usize) {
+    if skipped > 0 {
+        say!("Skipped {} malformed lines in {}", skipped, path.display());
+    }
+}
+
+fn load_known_peers() -> KnownPeers {
+    let path = KnownPeers::default_path().expect("No config directory to keep known peers in");
+    let known_peers = KnownPeers::load(&path).unwrap();
+    report_skipped(&path, known_peers.skipped());
+    known_peers
+}
+
+fn open_history(config: &Config) -> History {
+    let path = history_path(config);
+    let history = History::open(&path).unwrap();
+    report_skipped(&path, history.skipped());
+    history
+}
+
+/// Where the history is kept: wherever the config says, or the data directory.
+fn history_path(config: &Config) -> PathBuf {
+    config
+        .history_path
+        .clone()
+        .or_else(History::default_path)
+        .expect("No data directory to keep history in")
+}
+
+/// Where to advertise and browse: on each configured interface, or on all of them. Exits if an
+/// interface doesn't exist, so call it before taking over the terminal.
+fn scopes(config: &Config) -> Vec<dnssd::Scope> {
+    let scope = |interface| dnssd::Scope {
+        service_type: config.service_type.clone(),
+        domain: config.domain.clone(),
+        interface,
+    };
+    if config.interfaces.is_empty() {
+        return vec![scope(0)];
+    }
+    config
+        .interfaces
+        .iter()
+        .map(|name| match dnssd::interface_index(name) {
+            Some(index) => scope(index),
+            None => {
+                println!("No such network interface: {}", name);
+                process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// Where to accept connections: on the addresses of each configured interface, or on all of
+/// them. Exits if an interface doesn't exist or has no address.
+fn listen_addrs(config: &Config) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for name in &config.interfaces {
+        let found = match dnssd::interface_index(name) {
+            Some(_) => dnssd::interface_addresses(name),
+            None => {
+                println!("No such network interface: {}", name);
+                process::exit(1);
+            }
+        };
+        match found {
+            Ok(ref found) if found.is_empty() => {
+                println!("Network interface {} has no addresses to listen on", name);
+                process::exit(1);
+            }
+            Ok(found) => addrs.extend(found),
+            Err(err) => {
+                println!("Can't find the addresses of {}: {}", name, err);
+                process::exit(1);
+            }
+        }
+    }
+    for addr in &mut addrs {
+        addr.set_port(config.port);
+    }
+    addrs
+}
+
+/// Browses for peers in all of `scopes` at once.
+fn discover(
+    scopes: &[dnssd::Scope],
+) -> Result<Box<dyn Stream<Item = PeerEvent, Error = dnssd::Error> + Send>, dnssd::Error> {
+    let mut peers: Box<dyn Stream<Item = PeerEvent, Error = dnssd::Error> + Send> =
+        Box::new(stream::empty());
+    for scope in scopes {
+        peers = Box::new(peers.select(track_peers(scope)?));
+    }
+    Ok(peers)
+}
+
+fn make_node(config: &Config) -> chat::Node {
+    let nickname = match config.nickname {
+        Some(ref nick) => nicknames::normalize(nick).unwrap_or_else(|| {
+            println!("Not a usable nickname: {:?}", nick);
+            process::exit(1);
+        }),
+        None => default_nickname(),
+    };
+    let identity_path = Identity::default_path().expect("No config directory to keep identity in");
+    chat::Node {
+        keys: Keys::generate().unwrap(),
+        identity: Identity::load_or_generate(&identity_path).unwrap(),
+        nickname: Arc::new(Mutex::new(nickname)),
+        servicename: Arc::new(Mutex::new(None)),
+        known_peers: Arc::new(Mutex::new(load_known_peers())),
+        on_key_change: config.on_key_change,
+        limit: config.rate_limit.clone(),
+        heartbeat: config.heartbeat.clone(),
+        backoff: Backoff::default(),
+        expected_files: Expected::default(),
+        port: config.port,
+    }
+}
+
+/// `localchat chat` and `localchat listen`: join the network and show what arrives, taking
+/// input only if `interactive`.
+fn chat_command(config: &Config, interactive: bool) {
+    let outbox_path = Outbox::default_path().expect("No config directory to keep outbox in");
+    // A week's worth of messages, within reason
+    let outbox = Outbox::load(&outbox_path, 1000, Duration::from_secs(7 * 24 * 60 * 60)).unwrap();
+    report_skipped(&outbox_path, outbox.skipped());
+    let history = open_history(config);
+    let node = make_node(config);
+    let state = Arc::new(Mutex::new(State::new(
+        &node,
+        config.away_after,
+        outbox,
+        history,
+        config.relay,
+    )));
+    if let Some(ref group) = config.service_type.subtype {
+        // So that nodes browsing the plain type can tell we're not theirs
+        state.lock().unwrap().txt_record.insert(GROUP_KEY, group);
+    }
+    let (tx, rx): (
+        mpsc::UnboundedSender<chat::Event>,
+        mpsc::UnboundedReceiver<chat::Event>,
+    ) = mpsc::unbounded();
+    let log_connections_task = rx.for_each({
+        let state = Arc::clone(&state);
+        let node = node.clone();
+        let tx = tx.clone();
+        move |event| {
+            handle_event(&state, &node, &tx, event);
+            Ok(())
+        }
+    });
+    // Listen and start discovery before taking over the terminal, so errors are there to read
+    let server_task =
+        chat::server(node.clone(), tx.clone(), &listen_addrs(config)).unwrap_or_else(|err| {
+            println!("Can't listen on port {}: {}", config.port, err);
+            process::exit(1);
+        });
+    let registrations_task = match config.discovery {
+        Discovery::Dnssd => {
+            let scopes = scopes(config);
+            let started = scopes
+                .iter()
+                .map(|scope| register_service_task(Arc::clone(&state), scope, &node))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|registrations| {
+                    let track_peers_task =
+                        track_peers_task(Arc::clone(&state), &scopes, node.clone(), tx.clone())?;
+                    Ok(future::join_all(registrations).and_then(move |_| track_peers_task))
+                });
+            match started {
+                Ok(task) => Some(task),
+                Err(err) => {
+                    println!("Can't start discovery: {:?}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        Discovery::None => None,
+    };
+    // Without a terminal to draw on, fall back to plain lines
+    let plain = config.plain || !io::stdin().is_terminal() || !io::stdout().is_terminal();
+    if interactive && !plain {
+        match Terminal::enter() {
+            Ok(terminal) => {
+                *UI.lock().unwrap() = Some(Ui {
+                    terminal,
+                    screen: Screen::new(),
+                    names: Vec::new(),
+                })
+            }
+            Err(err) => println!("Can't take over the terminal, so staying plain: {}", err),
+        }
+    }
+    let full_screen = UI.lock().unwrap().is_some();
+    let client = Client {
+        state: Arc::clone(&state),
+        node: node.clone(),
+        events: tx.clone(),
+        commands: Arc::new(commands()),
+    };
+    let input_task = if full_screen {
+        Either::A(screen_input_task(client))
+    } else {
+        Either::B(input_task(client))
+    };
+    let screen_task = screen_task(Arc::clone(&state), node.clone());
+    let idle_task = idle_task(Arc::clone(&state));
+    let receipts_task = receipts_task(Arc::clone(&state));
+    let display_task = display_task(Arc::clone(&state), config.hooks.clone());
+    let static_peers_task =
+        static_peers_task(Arc::clone(&state), node.clone(), tx.clone(), &config.peers);
+    if registrations_task.is_none() {
+        say!("Discovery is off; waiting for peers to connect");
+    }
+    tokio::run(lazy(move || {
+        tokio::spawn(server_task.join(log_connections_task).map(|_| ()));
+        if let Some(registrations_task) = registrations_task {
+            tokio::spawn(registrations_task);
+        }
+        if interactive {
+            tokio::spawn(input_task);
+        }
+        tokio::spawn(static_peers_task);
+        tokio::spawn(idle_task);
+        tokio::spawn(receipts_task);
+        tokio::spawn(display_task);
+        if full_screen {
+            tokio::spawn(screen_task);
+        }
+        Ok(())
+    }));
+}
+
+fn main() {
+    let (command, options) = match cli::parse(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("{}\n\n{}", err, cli::USAGE);
+            process::exit(1);
+        }
+    };
+    // A config file asked for by name has to be there; the default one doesn't
+    let (config_path, config) = match options.config {
+        Some(ref path) => (path.clone(), Config::read(path)),
+        None => {
+            let path =
+                Config::default_path().expect("No config directory to look for a config file in");
+            let config = Config::load(&path);
+            (path, config)
+        }
+    };
+    let mut config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Error occurred reading {}: {}", config_path.display(), err);
+            process::exit(1);
+        }
+    };
+    config.apply(&options);
+    match command {
+        cli::Command::Chat => chat_command(&config, true),
+        cli::Command::Listen => chat_command(&config, false),
+        cli::Command::Peers { wait } => peers_command(&config, wait),
+        cli::Command::Send { peer, message } => send_command(&config, peer, message),
+        cli::Command::History { count } => history_command(&config, count),
+        cli::Command::KnownPeers => known_peers_command(&load_known_peers()),
+        cli::Command::Trust { servicename } => trust_command(&mut load_known_peers(), &servicename),
+        cli::Command::Config => config_command(&config_path, config),
+        cli::Command::Help => println!("{}", cli::USAGE),
+    }
+}
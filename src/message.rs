@@ -0,0 +1,288 @@
+use bytes::{BufMut, BytesMut};
+use hex;
+use rand;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gossip;
+use identity::{Identity, NodeId, PublicKey};
+use presence::Presence;
+use transfer::{Chunk, Offer};
+
+/// Everything sent over a connection. On the wire each frame is a single line of JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    Message(Message),
+    /// The sender has joined a channel.
+    Join {
+        channel: String,
+    },
+    /// The sender has left a channel.
+    Leave {
+        channel: String,
+    },
+    /// The sender has changed its nickname.
+    Nick {
+        nick: String,
+    },
+    /// The sender's presence has changed.
+    Presence(Presence),
+    /// Asks the peer to show it's still there.
+    Ping,
+    /// The answer to a `Ping`.
+    Pong,
+    /// The message with this id has been handed to the application.
+    Ack {
+        id: String,
+    },
+    /// The sender dropped something we sent it because we exceeded its rate limit.
+    RateLimited,
+    /// Asks for the messages in a channel (or outside any channel, if it's `None`) that came
+    /// after the message with id `after`, or failing that, were sent after `since`.
+    HistoryRequest {
+        channel: Option<String>,
+        since: u64,
+        after: Option<String>,
+    },
+    /// The answer to a `HistoryRequest`, oldest first.
+    History {
+        messages: Vec<Message>,
+    },
+    /// The sender would like to send us a file.
+    FileOffer(Offer),
+    /// The sender wants the file it was offered, from `offset` on.
+    FileAccept {
+        id: String,
+        offset: u64,
+    },
+    /// The sender doesn't want the file it was offered.
+    FileReject {
+        id: String,
+    },
+    /// The sender has written the first `received` bytes of the file.
+    FileProgress {
+        id: String,
+        received: u64,
+    },
+    /// The sender has the whole file, and it did or didn't match the offered hash.
+    FileDone {
+        id: String,
+        ok: bool,
+    },
+    /// Part of a file. It's sent as a binary record rather than as JSON.
+    #[serde(skip)]
+    FileChunk(Chunk),
+}
+
+/// A chat message, signed by its author so it can be attributed even after being relayed or
+/// stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    /// Random and unique to the message, so that acknowledgements can refer to it
+    pub id: String,
+    pub from: NodeId,
+    /// The author's nickname when it sent the message
+    pub nick: String,
+    /// The only recipient of a private message. `None` for messages to everyone.
+    pub to: Option<NodeId>,
+    /// The channel the message was sent to. `None` for messages outside any channel.
+    pub channel: Option<String>,
+    /// Hex-encoded identity key of the author. `from` must be derived from it.
+    pub key: String,
+    /// Milliseconds since the Unix epoch, according to the author
+    pub timestamp: u64,
+    /// The author's Lamport clock when it sent the message, which every node orders messages by
+    pub clock: u64,
+    pub body: String,
+    /// Hex-encoded signature over `signed_bytes`
+    pub signature: String,
+    /// How many more hops relays may carry the message. It isn't signed, since each relay
+    /// lowers it.
+    #[serde(default)]
+    pub ttl: u8,
+}
+
+impl Message {
+    /// A message for everyone we're connected to.
+    pub fn new(identity: &Identity, nick: String, clock: u64, body: String) -> Self {
+        Message::signed(identity, nick, None, None, clock, body)
+    }
+
+    /// A private message, to be delivered to `to` alone.
+    pub fn direct(identity: &Identity, nick: String, to: NodeId, clock: u64, body: String) -> Self {
+        Message::signed(identity, nick, Some(to), None, clock, body)
+    }
+
+    /// A message for the members of `channel`.
+    pub fn to_channel(
+        identity: &Identity,
+        nick: String,
+        channel: String,
+        clock: u64,
+        body: String,
+    ) -> Self {
+        Message::signed(identity, nick, None, Some(channel), clock, body)
+    }
+
+    fn signed(
+        identity: &Identity,
+        nick: String,
+        to: Option<NodeId>,
+        channel: Option<String>,
+        clock: u64,
+        body: String,
+    ) -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut message = Message {
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            from: identity.node_id(),
+            nick,
+            to,
+            channel,
+            key: hex::encode(identity.public_key().to_bytes()),
+            timestamp: since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis()),
+            clock,
+            body,
+            signature: String::new(),
+            ttl: gossip::DEFAULT_TTL,
+        };
+        message.signature = hex::encode(&identity.sign(&message.signed_bytes())[..]);
+        message
+    }
+
+    /// The fields covered by the signature. Variable-length fields are prefixed by their length
+    /// so that no two messages encode the same way.
+    fn signed_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        put_field(&mut bytes, self.id.as_bytes());
+        put_field(&mut bytes, self.from.as_str().as_bytes());
+        put_field(&mut bytes, self.nick.as_bytes());
+        put_optional_field(&mut bytes, self.to.as_ref().map(NodeId::as_str));
+        put_optional_field(&mut bytes, self.channel.as_deref());
+        bytes.reserve(16);
+        bytes.put_u64_be(self.timestamp);
+        bytes.put_u64_be(self.clock);
+        put_field(&mut bytes, self.body.as_bytes());
+        bytes
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.to.is_some()
+    }
+
+    /// Whether the message was signed by the identity it claims to be from.
+    pub fn verify(&self) -> bool {
+        let key = match hex::decode(&self.key)
+            .ok()
+            .and_then(|key| PublicKey::from_bytes(&key))
+        {
+            Some(key) => key,
+            None => return false,
+        };
+        let signature = match hex::decode(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        key.node_id() == self.from && key.verify(&self.signed_bytes(), &signature)
+    }
+}
+
+fn put_field(bytes: &mut BytesMut, field: &[u8]) {
+    bytes.reserve(4 + field.len());
+    bytes.put_u32_be(field.len() as u32);
+    bytes.put_slice(field);
+}
+
+/// Absent fields are encoded as empty, which can't be confused with a real node id or channel.
+fn put_optional_field(bytes: &mut BytesMut, field: Option<&str>) {
+    put_field(bytes, field.unwrap_or("").as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> (Identity, Message) {
+        let identity = Identity::generate();
+        let message = Message::to_channel(
+            &identity,
+            "alice".to_owned(),
+            "general".to_owned(),
+            7,
+            "hello".to_owned(),
+        );
+        (identity, message)
+    }
+
+    #[test]
+    fn a_signed_message_verifies() {
+        let (identity, message) = message();
+        assert!(message.verify());
+        let direct = Message::direct(
+            &identity,
+            "alice".to_owned(),
+            Identity::generate().node_id(),
+            8,
+            "psst".to_owned(),
+        );
+        assert!(direct.verify());
+    }
+
+    #[test]
+    fn a_tampered_message_fails_to_verify() {
+        let (_, original) = message();
+        let tampered: &[fn(&mut Message)] = &[
+            |message| message.body.push('!'),
+            |message| message.nick = "mallory".to_owned(),
+            |message| message.id = hex::encode([0u8; 16]),
+            |message| message.channel = None,
+            |message| message.channel = Some("random".to_owned()),
+            |message| message.to = Some(Identity::generate().node_id()),
+            |message| message.timestamp += 1,
+            |message| message.clock += 1,
+            |message| message.signature = "00".repeat(64),
+            |message| message.signature = "not hex".to_owned(),
+            |message| message.key = "not hex".to_owned(),
+        ];
+        for tamper in tampered {
+            let mut message = original.clone();
+            tamper(&mut message);
+            assert!(!message.verify(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn a_message_re_signed_by_someone_else_is_not_from_the_author() {
+        let (_, original) = message();
+        let mallory = Identity::generate();
+        let mut forged = original.clone();
+        forged.key = hex::encode(mallory.public_key().to_bytes());
+        forged.signature = hex::encode(&mallory.sign(&forged.signed_bytes())[..]);
+        assert!(!forged.verify());
+
+        // Owning up to it makes it Mallory's message, not Alice's
+        forged.from = mallory.node_id();
+        forged.signature = hex::encode(&mallory.sign(&forged.signed_bytes())[..]);
+        assert!(forged.verify());
+        assert_ne!(forged.from, original.from);
+    }
+
+    #[test]
+    fn relays_can_lower_the_ttl_without_breaking_the_signature() {
+        let (_, mut message) = message();
+        message.ttl -= 1;
+        assert!(message.verify());
+    }
+
+    #[test]
+    fn fields_cannot_be_shifted_between_each_other() {
+        let identity = Identity::generate();
+        let a = Message::new(&identity, "ab".to_owned(), 1, "c".to_owned());
+        let mut b = a.clone();
+        b.nick = "a".to_owned();
+        b.body = "bc".to_owned();
+        assert_ne!(a.signed_bytes(), b.signed_bytes());
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use identity::NodeId;
+
+pub const MAX_LEN: usize = 32;
+
+/// Turns user input into a nickname, or `None` if it can't be one.
+pub fn normalize(nick: &str) -> Option<String> {
+    let nick = nick.trim();
+    if nick.is_empty()
+        || nick.chars().count() > MAX_LEN
+        || nick.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return None;
+    }
+    Some(nick.to_owned())
+}
+
+/// The nickname each node on the LAN claims, including our own.
+///
+/// Nicknames aren't unique, so when several nodes claim the same one it goes to the node with
+/// the lowest node id, and the rest are shown with a node id suffix. Every node applies the same
+/// rule to the same claims, so everyone agrees on who is who.
+#[derive(Debug, Default)]
+pub struct Nicknames {
+    claims: HashMap<NodeId, String>,
+}
+
+impl Nicknames {
+    pub fn new() -> Self {
+        Nicknames::default()
+    }
+
+    pub fn claim(&mut self, node_id: NodeId, nick: String) {
+        self.claims.insert(node_id, nick);
+    }
+
+    pub fn remove(&mut self, node_id: &NodeId) {
+        self.claims.remove(node_id);
+    }
+
+    /// The node that gets to use `nick` unadorned, if anyone claims it.
+    fn owner(&self, nick: &str) -> Option<&NodeId> {
+        self.claims
+            .iter()
+            .filter(|(_, claimed)| claimed.as_str() == nick)
+            .map(|(node_id, _)| node_id)
+            .min()
+    }
+
+    /// Whether `node_id` gets to use the nickname it claims.
+    pub fn owns_claim(&self, node_id: &NodeId) -> bool {
+        match self.claims.get(node_id) {
+            Some(nick) => self.owner(nick) == Some(node_id),
+            None => false,
+        }
+    }
+
+    /// The name to show for `node_id`: its nickname, disambiguated if someone else owns it, or
+    /// its node id if it hasn't claimed one.
+    pub fn display_name(&self, node_id: &NodeId) -> String {
+        match self.claims.get(node_id) {
+            Some(nick) if self.owns_claim(node_id) => nick.clone(),
+            Some(nick) => {
+                let id = node_id.as_str();
+                format!("{}~{}", nick, id.get(..8).unwrap_or(id))
+            }
+            None => node_id.to_string(),
+        }
+    }
+
+    /// Finds a node by the name `display_name` gives it.
+    pub fn find(&self, name: &str) -> Option<&NodeId> {
+        self.claims
+            .keys()
+            .find(|node_id| self.display_name(node_id) == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, &str)> {
+        self.claims
+            .iter()
+            .map(|(node_id, nick)| (node_id, nick.as_str()))
+    }
+}
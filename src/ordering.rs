@@ -0,0 +1,110 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use identity::NodeId;
+use message::Message;
+
+/// A Lamport clock. Stamping every message with it means that a message always sorts after any
+/// message its author had seen when writing it.
+#[derive(Debug, Default)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl LamportClock {
+    pub fn new() -> Self {
+        LamportClock::default()
+    }
+
+    /// Advances the clock for a message we're about to send, and returns its stamp.
+    pub fn tick(&mut self) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    /// Catches up with the stamp on a message we've received.
+    pub fn observe(&mut self, time: u64) {
+        self.time = self.time.max(time);
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+}
+
+/// Where a message belongs in the order every node agrees on: by clock, then by author, then by
+/// id, so that concurrent messages are still ordered the same way everywhere.
+type Key = (u64, NodeId, String);
+
+fn key(message: &Message) -> Key {
+    (message.clock, message.from.clone(), message.id.clone())
+}
+
+/// Compares messages by where they belong in the agreed order, for sorting.
+pub fn cmp(a: &Message, b: &Message) -> Ordering {
+    key(a).cmp(&key(b))
+}
+
+/// Holds received messages for a short while so that ones which arrive out of order can be put
+/// back in order before they're shown.
+///
+/// Messages are released in clock order, each once it has been held for the hold time. A
+/// message that turns up after messages that sort after it have been released can't be put
+/// back in place, so it's released as soon as it's due.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    hold: Duration,
+    pending: BTreeMap<Key, (Instant, Message)>,
+}
+
+impl ReorderBuffer {
+    pub fn new(hold: Duration) -> Self {
+        ReorderBuffer {
+            hold,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Holds `message`, which arrived at `now`. Returns `false` if it was already held.
+    pub fn push(&mut self, message: Message, now: Instant) -> bool {
+        let key = key(&message);
+        if self.pending.contains_key(&key) {
+            return false;
+        }
+        self.pending.insert(key, (now + self.hold, message));
+        true
+    }
+
+    /// Releases, in order, the messages at the front of the buffer that have been held long
+    /// enough by `now`.
+    pub fn pop_ready(&mut self, now: Instant) -> Vec<Message> {
+        let mut ready = Vec::new();
+        loop {
+            let key = match self.pending.iter().next() {
+                Some((key, (due, _))) if *due <= now => key.clone(),
+                _ => return ready,
+            };
+            if let Some((_, message)) = self.pending.remove(&key) {
+                ready.push(message);
+            }
+        }
+    }
+
+    /// Releases everything, in order, however long it's been held.
+    pub fn flush(&mut self) -> Vec<Message> {
+        let pending = std::mem::take(&mut self.pending);
+        pending
+            .into_iter()
+            .map(|(_, (_, message))| message)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
@@ -0,0 +1,203 @@
+use dirs;
+use serde_json;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use identity::NodeId;
+use message::Message;
+
+/// Direct messages waiting for their recipient to come back, kept on disk so they survive a
+/// restart.
+///
+/// The file has one message per line, as JSON.
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    /// The most messages the outbox holds at once
+    max_messages: usize,
+    /// How long a message waits before it's given up on
+    max_age: Duration,
+    messages: Vec<Message>,
+    /// How many lines of the file couldn't be read
+    skipped: usize,
+}
+
+impl Outbox {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("localchat").join("outbox"))
+    }
+
+    /// Loads the outbox at `path`, dropping anything that has expired. A missing file is an
+    /// empty outbox.
+    pub fn load(path: &Path, max_messages: usize, max_age: Duration) -> io::Result<Self> {
+        let mut messages = Vec::new();
+        let mut skipped = 0;
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    match serde_json::from_str(line) {
+                        Ok(message) => messages.push(message),
+                        Err(_) => skipped += 1,
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let mut outbox = Outbox {
+            path: path.to_owned(),
+            max_messages,
+            max_age,
+            messages,
+            skipped,
+        };
+        outbox.expire();
+        Ok(outbox)
+    }
+
+    /// How many malformed lines were skipped when the outbox was loaded. They're lost when it's
+    /// next saved.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Private messages are only for our eyes, and those of their recipients
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        // The mode only applies to new files, and older versions made this one readable by all
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        for message in &self.messages {
+            // Serializing a `Message` can't fail: it's all strings and integers
+            writeln!(file, "{}", serde_json::to_string(message).unwrap())?;
+        }
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let max_age = self.max_age;
+        self.messages
+            .retain(|message| since_epoch < Duration::from_millis(message.timestamp) + max_age);
+    }
+
+    /// Queues a direct message until its recipient is back. Returns `false` if the outbox is
+    /// full. Only direct messages can be queued.
+    pub fn push(&mut self, message: Message) -> io::Result<bool> {
+        if message.to.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only direct messages can be queued",
+            ));
+        }
+        self.expire();
+        if self.messages.len() >= self.max_messages {
+            return Ok(false);
+        }
+        self.messages.push(message);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// The unexpired messages queued for `node_id`, oldest first. They stay queued until
+    /// they're removed, so that nothing is lost if the peer drops them.
+    pub fn queued_for(&mut self, node_id: &NodeId) -> Vec<&Message> {
+        self.expire();
+        self.messages
+            .iter()
+            .filter(|message| message.to.as_ref() == Some(node_id))
+            .collect()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.messages.iter().any(|message| message.id == id)
+    }
+
+    /// Removes the message with id `id`, once it's been delivered. Returns `false` if it wasn't
+    /// queued.
+    pub fn remove(&mut self, id: &str) -> io::Result<bool> {
+        let len = self.messages.len();
+        self.messages.retain(|message| message.id != id);
+        if self.messages.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identity::Identity;
+    use rand;
+    use std::env;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir()
+            .join(format!("localchat-test-{}", rand::random::<u64>()))
+            .join("outbox")
+    }
+
+    #[test]
+    fn messages_stay_queued_until_removed() {
+        let path = temp_path();
+        let max_age = Duration::from_secs(60);
+        let mut outbox = Outbox::load(&path, 10, max_age).unwrap();
+        let alice = Identity::generate();
+        let bob = Identity::generate().node_id();
+        let carol = Identity::generate().node_id();
+        let first = Message::direct(&alice, "alice".into(), bob.clone(), 1, "one".into());
+        let second = Message::direct(&alice, "alice".into(), bob.clone(), 2, "two".into());
+        let other = Message::direct(&alice, "alice".into(), carol.clone(), 3, "three".into());
+        for message in &[&first, &second, &other] {
+            assert!(outbox.push((*message).clone()).unwrap());
+        }
+
+        let queued: Vec<&str> = outbox
+            .queued_for(&bob)
+            .iter()
+            .map(|message| message.body.as_str())
+            .collect();
+        assert_eq!(queued, vec!["one", "two"]);
+        assert_eq!(outbox.len(), 3);
+
+        assert!(outbox.remove(&first.id).unwrap());
+        assert!(!outbox.remove(&first.id).unwrap());
+        assert!(!outbox.contains(&first.id));
+
+        // What's left survives a restart
+        let mut outbox = Outbox::load(&path, 10, max_age).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.queued_for(&bob)[0].id, second.id);
+        assert_eq!(outbox.queued_for(&carol)[0].id, other.id);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn only_direct_messages_can_be_queued() {
+        let mut outbox = Outbox::load(&temp_path(), 10, Duration::from_secs(60)).unwrap();
+        let message = Message::new(&Identity::generate(), "alice".into(), 1, "hi".into());
+        assert!(outbox.push(message).is_err());
+    }
+}
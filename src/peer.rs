@@ -1,9 +1,14 @@
+use futures::future::Either;
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
 use tokio::prelude::*;
 
 use super::NetworkEvent;
 
+use channels;
 use dnssd;
+use identity::NodeId;
+use presence::Presence;
 
 #[derive(Debug)]
 pub struct PeerEvent {
@@ -11,29 +16,75 @@ pub struct PeerEvent {
     pub event: NetworkEvent,
 }
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Peer {
     pub servicename: String,
+    /// The index of the interface the peer was found on. Configured peers have 0.
+    pub interface: u32,
     pub hostname: String,
     pub socket_addr: SocketAddr,
+    /// Fingerprint of the peer's transport key, as advertised in its TXT record. Configured
+    /// peers have none, and are checked by node id alone.
+    pub fingerprint: Option<String>,
+    /// The node id the peer advertised. It's only trustworthy once a connection to the peer
+    /// has verified it.
+    pub node_id: Option<NodeId>,
+    /// The channels the peer was in when it was resolved
+    pub channels: BTreeSet<String>,
+    /// The peer's presence, as last advertised or announced
+    pub presence: Presence,
 }
 
-fn find_peer(service: &dnssd::Service) -> impl Future<Item = Peer, Error = dnssd::Error> {
+/// The TXT record key a node advertises its service subtype under, if it has one.
+pub const GROUP_KEY: &str = "grp";
+
+/// Resolves `service`, or `None` if it belongs to a different group than `group`.
+fn find_peer(
+    service: &dnssd::Service,
+    group: Option<String>,
+) -> impl Future<Item = Option<Peer>, Error = dnssd::Error> {
     let servicename = service.name.clone();
+    let interface = service.interface;
     // TODO: remove these unwraps
-    dnssd::resolve_service(&service).unwrap().and_then(|host| {
-        dnssd::get_address(&host).unwrap().map(|addr| Peer {
-            servicename: servicename,
-            hostname: host.name,
-            socket_addr: SocketAddr::new(addr, host.port),
+    dnssd::resolve_service(&service)
+        .unwrap()
+        .and_then(move |host| {
+            // Browsing a plain type finds every subtype's services too
+            if host.txt.get(GROUP_KEY) != group.as_deref() {
+                return Either::A(future::ok(None));
+            }
+            Either::B(dnssd::get_address(&host).unwrap().map(move |addr| {
+                Some(Peer {
+                    servicename: servicename,
+                    interface,
+                    fingerprint: host.txt.get("fp").map(|fp| fp.to_owned()),
+                    node_id: host.txt.get("id").map(NodeId::from),
+                    channels: host
+                        .txt
+                        .get("ch")
+                        .map(channels::from_txt)
+                        .unwrap_or_default(),
+                    presence: host
+                        .txt
+                        .get("ps")
+                        .and_then(Presence::from_txt)
+                        .unwrap_or_default(),
+                    hostname: host.name,
+                    socket_addr: SocketAddr::new(addr, host.port),
+                })
+            }))
         })
-    })
 }
 
-pub fn track_peers() -> Result<impl Stream<Item = PeerEvent, Error = dnssd::Error>, dnssd::Error> {
-    Ok(
-        dnssd::browse_services()?.and_then(|dnssd::ServiceEvent { service, event }| {
-            find_peer(&service).map(|peer| PeerEvent { peer, event })
-        }),
-    )
+/// Browses `scope` for peers, leaving out any whose group doesn't match its subtype.
+pub fn track_peers(
+    scope: &dnssd::Scope,
+) -> Result<impl Stream<Item = PeerEvent, Error = dnssd::Error>, dnssd::Error> {
+    let group = scope.service_type.subtype.clone();
+    Ok(dnssd::browse_services(scope)?
+        .and_then(move |dnssd::ServiceEvent { service, event }| {
+            find_peer(&service, group.clone())
+                .map(|peer| peer.map(|peer| PeerEvent { peer, event }))
+        })
+        .filter_map(|event| event))
 }
@@ -0,0 +1,138 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How available a node's user is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Away,
+    Busy,
+    /// Either gone, or pretending to be
+    Offline,
+}
+
+impl Status {
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "online" => Some(Status::Online),
+            "away" => Some(Status::Away),
+            "busy" => Some(Status::Busy),
+            "offline" => Some(Status::Offline),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Online => "online",
+            Status::Away => "away",
+            Status::Busy => "busy",
+            Status::Offline => "offline",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A status with an optional message, like "away: lunch".
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Presence {
+    pub status: Status,
+    pub text: Option<String>,
+}
+
+impl Presence {
+    pub fn new(status: Status, text: Option<String>) -> Self {
+        Presence {
+            status,
+            text: text.filter(|text| !text.is_empty()),
+        }
+    }
+
+    pub fn online() -> Self {
+        Presence::new(Status::Online, None)
+    }
+
+    /// Encodes the presence for the `ps` TXT record entry, as the status optionally followed by
+    /// a colon and the text.
+    pub fn to_txt(&self) -> String {
+        match self.text {
+            Some(ref text) => format!("{}:{}", self.status, text),
+            None => self.status.to_string(),
+        }
+    }
+
+    /// Decodes the `ps` TXT record entry.
+    pub fn from_txt(txt: &str) -> Option<Self> {
+        let mut parts = txt.splitn(2, ':');
+        let status = Status::parse(parts.next()?)?;
+        Some(Presence::new(status, parts.next().map(str::to_owned)))
+    }
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Presence::online()
+    }
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.text {
+            Some(ref text) => write!(f, "{}: {}", self.status, text),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
+/// Switches the user to away when they haven't typed anything for a while, and back when they
+/// do. A status the user set themselves is left alone.
+#[derive(Debug)]
+pub struct IdleTracker {
+    /// How long until the user counts as idle. `None` disables auto-away.
+    away_after: Option<Duration>,
+    last_input: Instant,
+    /// Whether the current away status was set by us rather than the user
+    auto_away: bool,
+}
+
+impl IdleTracker {
+    pub fn new(away_after: Option<Duration>) -> Self {
+        IdleTracker {
+            away_after,
+            last_input: Instant::now(),
+            auto_away: false,
+        }
+    }
+
+    /// Notes that the user typed something. Returns the presence to switch back to if they
+    /// were auto-away.
+    pub fn input(&mut self) -> Option<Presence> {
+        self.last_input = Instant::now();
+        if !self.auto_away {
+            return None;
+        }
+        self.auto_away = false;
+        Some(Presence::online())
+    }
+
+    /// Notes that the user set their presence themselves.
+    pub fn set_manually(&mut self) {
+        self.auto_away = false;
+    }
+
+    /// Returns the presence to switch to if the user has gone idle while online.
+    pub fn check(&mut self, current: &Presence) -> Option<Presence> {
+        let away_after = self.away_after?;
+        if current.status != Status::Online || self.last_input.elapsed() < away_after {
+            return None;
+        }
+        self.auto_away = true;
+        Some(Presence::new(Status::Away, Some("idle".to_owned())))
+    }
+}
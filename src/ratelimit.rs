@@ -0,0 +1,196 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// What a connection does with an incoming line that exceeds its rate limit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// Silently discard the line.
+    Drop,
+    /// Hold the line (and stop reading from the socket) until it fits in the limit.
+    Delay,
+    /// Discard the line and tell the sender it was dropped.
+    Warn,
+    /// Close the connection.
+    Disconnect,
+}
+
+impl Violation {
+    pub fn parse(violation: &str) -> Option<Self> {
+        match violation {
+            "drop" => Some(Violation::Drop),
+            "delay" => Some(Violation::Delay),
+            "warn" => Some(Violation::Warn),
+            "disconnect" => Some(Violation::Disconnect),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Violation::Drop => "drop",
+            Violation::Delay => "delay",
+            Violation::Warn => "warn",
+            Violation::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// Per-connection limits on incoming chat messages. A rate of zero disables that limit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u32,
+    pub on_violation: Violation,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages_per_sec: 10,
+            bytes_per_sec: 16 * 1024,
+            on_violation: Violation::Warn,
+        }
+    }
+}
+
+/// A token bucket holding at most one second's worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// How long until `cost` tokens are available. Costs larger than the bucket are clamped
+    /// so that oversized messages are still let through once the bucket is full.
+    fn wait_for(&self, cost: f64) -> Duration {
+        let cost = cost.min(self.rate);
+        if self.tokens >= cost {
+            return Duration::from_secs(0);
+        }
+        let secs = (cost - self.tokens) / self.rate;
+        Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens -= cost.min(self.rate);
+    }
+}
+
+/// Tracks message and byte budgets for a single connection.
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        RateLimiter {
+            messages: TokenBucket::new(limit.messages_per_sec),
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+        }
+    }
+
+    /// Admits a message of `len` bytes if both budgets allow it. Otherwise nothing is consumed
+    /// and the error holds how long the caller should wait before trying again.
+    pub fn check(&mut self, len: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        let wait = cmp::max(self.messages.wait_for(1.0), self.bytes.wait_for(len as f64));
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+        self.messages.take(1.0);
+        self.bytes.take(len as f64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(messages_per_sec: u32, bytes_per_sec: u32) -> RateLimit {
+        RateLimit {
+            messages_per_sec,
+            bytes_per_sec,
+            on_violation: Violation::Drop,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_with_time_up_to_its_rate() {
+        let mut bucket = TokenBucket::new(10);
+        let start = bucket.last;
+        bucket.take(10.0);
+        assert_eq!(bucket.wait_for(1.0), Duration::from_millis(100));
+
+        bucket.refill(start + Duration::from_millis(500));
+        assert!((bucket.tokens - 5.0).abs() < 1e-9);
+
+        bucket.refill(start + Duration::from_secs(60));
+        assert!((bucket.tokens - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn oversized_costs_are_clamped_to_the_bucket() {
+        let mut bucket = TokenBucket::new(100);
+        assert_eq!(bucket.wait_for(1000.0), Duration::from_secs(0));
+        bucket.take(1000.0);
+        assert!(bucket.tokens.abs() < 1e-9);
+        assert_eq!(bucket.wait_for(1000.0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn check_admits_a_burst_then_says_how_long_to_wait() {
+        let mut limiter = RateLimiter::new(&limit(3, 1024));
+        for _ in 0..3 {
+            assert_eq!(limiter.check(10), Ok(()));
+        }
+        let wait = limiter.check(10).unwrap_err();
+        assert!(wait > Duration::from_millis(300) && wait <= Duration::from_millis(334));
+    }
+
+    #[test]
+    fn check_limits_bytes_as_well_as_messages() {
+        let mut limiter = RateLimiter::new(&limit(100, 100));
+        assert_eq!(limiter.check(80), Ok(()));
+        let wait = limiter.check(40).unwrap_err();
+        assert!(wait > Duration::from_millis(190) && wait <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn rejected_messages_use_up_nothing() {
+        let mut limiter = RateLimiter::new(&limit(1, 1024));
+        assert_eq!(limiter.check(1), Ok(()));
+        assert!(limiter.check(1).is_err());
+        assert!(limiter.messages.tokens >= 0.0);
+        assert!(limiter.bytes.tokens > 1022.0);
+    }
+
+    #[test]
+    fn a_zero_rate_disables_the_limit() {
+        let mut limiter = RateLimiter::new(&limit(0, 0));
+        for _ in 0..1000 {
+            assert_eq!(limiter.check(1 << 20), Ok(()));
+        }
+    }
+}
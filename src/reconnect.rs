@@ -0,0 +1,122 @@
+use futures::future::{self, Either, Loop};
+use futures::sync::{mpsc, oneshot};
+use rand::{self, Rng};
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use chat::{self, Event, Node};
+use peer::Peer;
+
+/// How long to wait between attempts to reach a peer.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// The wait after the first failed attempt
+    pub initial: Duration,
+    /// The most we'll ever wait
+    pub max: Duration,
+    /// How much longer each wait is than the last
+    pub multiplier: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2,
+        }
+    }
+}
+
+impl Backoff {
+    /// The wait after `failures` failed attempts in a row. It's somewhere between half and all
+    /// of the exponential delay, so that peers which lost each other at the same moment don't
+    /// keep retrying in lockstep.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 1..failures {
+            delay = delay.checked_mul(self.multiplier).unwrap_or(self.max);
+            if delay >= self.max {
+                break;
+            }
+        }
+        let millis = delay.min(self.max).as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
+}
+
+/// How long a connection has to stay open to count as a success. One that closes sooner counts
+/// as a failed attempt, so that a peer which keeps turning us away isn't redialled in a tight
+/// loop.
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+
+/// Where an outbound connection to a peer is at.
+#[derive(Debug)]
+pub enum ConnectionState {
+    Connecting,
+    /// Frames for the peer go to the sender until the state next changes.
+    Connected(chat::Sender),
+    /// The last attempt failed; the next one is after the delay.
+    BackingOff(Duration),
+}
+
+/// Cancels the task returned by `maintain` when dropped.
+pub type Handle = oneshot::Sender<()>;
+
+/// Keeps a connection open to `peer`, reconnecting whenever it drops, until the returned handle
+/// is dropped, which also closes the connection. Each change of state is sent to `tx` as an
+/// `Event::Connection`.
+pub fn maintain(
+    peer: Peer,
+    node: &Node,
+    tx: mpsc::UnboundedSender<Event>,
+) -> (impl Future<Item = (), Error = ()>, Handle) {
+    let node = node.clone();
+    let node_id = peer
+        .node_id
+        .clone()
+        .expect("Can only connect to peers with a node id");
+    let (handle, cancelled) = oneshot::channel();
+    let notify = move |tx: &mpsc::UnboundedSender<Event>, state| {
+        let _ = tx.unbounded_send(Event::Connection {
+            peer: node_id.clone(),
+            state,
+        });
+    };
+    let task = future::loop_fn(0, move |failures| {
+        notify(&tx, ConnectionState::Connecting);
+        // Waits out the backoff after `failures` failed attempts in a row
+        let retry = {
+            let backoff = node.backoff.clone();
+            let tx = tx.clone();
+            let notify = notify.clone();
+            move |failures| {
+                let delay = backoff.delay(failures);
+                notify(&tx, ConnectionState::BackingOff(delay));
+                Delay::new(Instant::now() + delay)
+                    .then(move |_| Ok::<_, ()>(Loop::<(), _>::Continue(failures)))
+            }
+        };
+        let tx = tx.clone();
+        let notify = notify.clone();
+        chat::connect(peer.clone(), &node, tx.clone()).then(move |result| match result {
+            Ok((sender, connection)) => {
+                notify(&tx, ConnectionState::Connected(sender));
+                let opened = Instant::now();
+                // Start over once the connection is gone, whatever the reason
+                Either::A(connection.then(move |_| {
+                    if opened.elapsed() >= STABLE_AFTER {
+                        Either::A(future::ok(Loop::Continue(0)))
+                    } else {
+                        Either::B(retry(failures + 1))
+                    }
+                }))
+            }
+            // `BackingOff` tells the application it failed
+            Err(_) => Either::B(retry(failures + 1)),
+        })
+    });
+    let task = task.select2(cancelled).then(|_| Ok(()));
+    (task, handle)
+}
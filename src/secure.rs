@@ -0,0 +1,617 @@
+use bytes::{BufMut, BytesMut};
+use hex;
+use sha2::{Digest, Sha256};
+use snow;
+use std::cmp;
+use std::io::{Read, Write};
+use std::time::Duration;
+use tokio::io;
+use tokio::prelude::*;
+use tokio::timer::Timeout;
+
+use identity::{Identity, NodeId, PublicKey};
+use serde_json;
+
+/// XX lets both sides learn each other's static key during the handshake, so neither needs to
+/// know the other's key up front.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Noise messages are limited to 64k, including the authentication tag.
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// How long the other end has to finish the handshake, so that a peer that connects and then
+/// says nothing doesn't tie up a task for good.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    NoiseError(snow::Error),
+    /// The remote static key doesn't match the fingerprint the peer advertised.
+    FingerprintMismatch {
+        expected: Option<String>,
+        actual: String,
+    },
+    /// The remote end's identity key is malformed or didn't sign its transport key.
+    InvalidIdentity,
+    /// The remote end's node id doesn't match the one the peer advertised.
+    IdentityMismatch {
+        expected: Option<NodeId>,
+        actual: NodeId,
+    },
+    /// The peer's service name is pinned to a different identity.
+    KeyChanged {
+        servicename: String,
+        pinned: NodeId,
+        actual: NodeId,
+    },
+    /// The other end didn't finish the handshake in time.
+    TimedOut,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<snow::Error> for Error {
+    fn from(err: snow::Error) -> Self {
+        Error::NoiseError(err)
+    }
+}
+
+/// The node's static Noise keypair.
+#[derive(Clone)]
+pub struct Keys {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Keys {
+    pub fn generate() -> Result<Self, Error> {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(Keys {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// The fingerprint advertised in the DNS-SD TXT record.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+}
+
+/// Hex-encoded SHA-256 of a static public key.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    hex::encode(Sha256::digest(public_key))
+}
+
+/// Writes a single length-prefixed handshake message.
+fn write_message<S: AsyncWrite>(
+    socket: S,
+    message: &[u8],
+) -> impl Future<Item = S, Error = io::Error> {
+    let mut buf = Vec::with_capacity(2 + message.len());
+    buf.put_u16_be(message.len() as u16);
+    buf.extend_from_slice(message);
+    io::write_all(socket, buf).map(|(socket, _)| socket)
+}
+
+/// Reads a single length-prefixed handshake message.
+fn read_message<S: AsyncRead>(socket: S) -> impl Future<Item = (S, Vec<u8>), Error = io::Error> {
+    io::read_exact(socket, [0u8; 2]).and_then(|(socket, len)| {
+        let len = (usize::from(len[0]) << 8) | usize::from(len[1]);
+        io::read_exact(socket, vec![0u8; len])
+    })
+}
+
+/// What each end tells the other about itself, in the encrypted handshake payload.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    /// Hex-encoded identity key
+    pub key: String,
+    /// Hex-encoded signature by the identity key over the sender's transport key, proving the
+    /// identity vouches for this connection
+    pub signature: String,
+    pub nickname: String,
+    /// The service name the sender is advertised under, once it's registered, so that the
+    /// other end can check the sender against the key it pinned for that name
+    #[serde(default)]
+    pub servicename: Option<String>,
+    /// Whether the sender can frame records with length prefixes rather than CRLF. They're only
+    /// used if both ends can.
+    #[serde(default)]
+    pub length_prefixed: bool,
+}
+
+impl Hello {
+    pub fn new(
+        keys: &Keys,
+        identity: &Identity,
+        nickname: String,
+        servicename: Option<String>,
+    ) -> Self {
+        Hello {
+            key: hex::encode(identity.public_key().to_bytes()),
+            signature: hex::encode(&identity.sign(&keys.public)[..]),
+            nickname,
+            servicename,
+            length_prefixed: true,
+        }
+    }
+
+    /// Checks the identity against the transport key the remote end used in the handshake.
+    fn verify(&self, remote_static: &[u8]) -> Result<PublicKey, Error> {
+        let key = hex::decode(&self.key)
+            .ok()
+            .and_then(|key| PublicKey::from_bytes(&key));
+        let signature = hex::decode(&self.signature).ok();
+        match (key, signature) {
+            (Some(key), Some(ref signature)) if key.verify(remote_static, signature) => Ok(key),
+            _ => Err(Error::InvalidIdentity),
+        }
+    }
+}
+
+fn write_handshake<S: AsyncWrite>(
+    socket: S,
+    mut noise: snow::HandshakeState,
+    payload: &[u8],
+) -> impl Future<Item = (S, snow::HandshakeState), Error = Error> {
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    future::result(noise.write_message(payload, &mut buf))
+        .from_err()
+        .and_then(move |len| {
+            write_message(socket, &buf[..len])
+                .from_err()
+                .map(|socket| (socket, noise))
+        })
+}
+
+/// Reads a handshake message, resolving to its payload.
+fn read_handshake<S: AsyncRead>(
+    socket: S,
+    mut noise: snow::HandshakeState,
+) -> impl Future<Item = (S, snow::HandshakeState, Vec<u8>), Error = Error> {
+    read_message(socket)
+        .from_err()
+        .and_then(move |(socket, message)| {
+            let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+            let len = noise.read_message(&message, &mut payload)?;
+            payload.truncate(len);
+            Ok((socket, noise, payload))
+        })
+}
+
+fn finish_handshake<S>(
+    socket: S,
+    noise: snow::HandshakeState,
+    remote_payload: &[u8],
+) -> Result<SecureStream<S>, Error> {
+    let remote_static = noise
+        .get_remote_static()
+        .expect("XX handshake always transmits the remote static key")
+        .to_vec();
+    let remote_hello: Hello =
+        serde_json::from_slice(remote_payload).map_err(|_| Error::InvalidIdentity)?;
+    let remote_identity = remote_hello.verify(&remote_static)?;
+    Ok(SecureStream {
+        socket,
+        noise: noise.into_transport_mode()?,
+        remote_static,
+        remote_identity,
+        remote_hello,
+        rd: BytesMut::new(),
+        plaintext: BytesMut::new(),
+        wr: BytesMut::new(),
+    })
+}
+
+/// Performs the initiator side of the handshake on a freshly connected socket. The two ends
+/// exchange `Hello`s in the encrypted handshake payloads.
+pub fn initiate<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    keys: &Keys,
+    hello: &Hello,
+) -> impl Future<Item = SecureStream<S>, Error = Error> {
+    // Serializing a `Hello` can't fail: it's all strings
+    let payload = serde_json::to_vec(hello).unwrap();
+    let noise = NOISE_PARAMS
+        .parse()
+        .map_err(Error::from)
+        .and_then(|params| {
+            snow::Builder::new(params)
+                .local_private_key(&keys.private)
+                .build_initiator()
+                .map_err(Error::from)
+        });
+    // -> e
+    let handshake = future::result(noise)
+        .and_then(|noise| write_handshake(socket, noise, &[]))
+        // <- e, ee, s, es
+        .and_then(|(socket, noise)| read_handshake(socket, noise))
+        // -> s, se
+        .and_then(move |(socket, noise, remote_payload)| {
+            write_handshake(socket, noise, &payload)
+                .and_then(move |(socket, noise)| finish_handshake(socket, noise, &remote_payload))
+        });
+    with_timeout(handshake)
+}
+
+/// Performs the responder side of the handshake on a freshly accepted socket.
+pub fn respond<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    keys: &Keys,
+    hello: &Hello,
+) -> impl Future<Item = SecureStream<S>, Error = Error> {
+    let payload = serde_json::to_vec(hello).unwrap();
+    let noise = NOISE_PARAMS
+        .parse()
+        .map_err(Error::from)
+        .and_then(|params| {
+            snow::Builder::new(params)
+                .local_private_key(&keys.private)
+                .build_responder()
+                .map_err(Error::from)
+        });
+    // <- e
+    let handshake = future::result(noise)
+        .and_then(|noise| read_handshake(socket, noise))
+        // -> e, ee, s, es
+        .and_then(move |(socket, noise, _)| write_handshake(socket, noise, &payload))
+        // <- s, se
+        .and_then(|(socket, noise)| read_handshake(socket, noise))
+        .and_then(|(socket, noise, remote_payload)| {
+            finish_handshake(socket, noise, &remote_payload)
+        });
+    with_timeout(handshake)
+}
+
+/// Fails `handshake` with `Error::TimedOut` if it takes longer than `HANDSHAKE_TIMEOUT`.
+fn with_timeout<F>(handshake: F) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    Timeout::new(handshake, HANDSHAKE_TIMEOUT).map_err(|err| {
+        if err.is_elapsed() {
+            return Error::TimedOut;
+        }
+        match err.into_inner() {
+            Some(err) => err,
+            None => Error::IoError(io::Error::other("handshake timer failed")),
+        }
+    })
+}
+
+/// A socket whose traffic is encrypted with the keys agreed on during the handshake.
+///
+/// On the wire each chunk of plaintext becomes one Noise message, prefixed by its length as a
+/// big-endian `u16`.
+pub struct SecureStream<S> {
+    socket: S,
+    noise: snow::TransportState,
+    remote_static: Vec<u8>,
+    remote_identity: PublicKey,
+    remote_hello: Hello,
+    /// Ciphertext read from the socket that hasn't been decrypted yet
+    rd: BytesMut,
+    /// Decrypted bytes that haven't been read yet
+    plaintext: BytesMut,
+    /// Ciphertext waiting to be written to the socket
+    wr: BytesMut,
+}
+
+impl<S> SecureStream<S> {
+    /// The fingerprint of the remote end's static key.
+    pub fn remote_fingerprint(&self) -> String {
+        fingerprint(&self.remote_static)
+    }
+
+    /// The remote end's identity key, already checked against its transport key.
+    pub fn remote_identity(&self) -> PublicKey {
+        self.remote_identity
+    }
+
+    /// What the remote end said about itself during the handshake.
+    pub fn remote_hello(&self) -> &Hello {
+        &self.remote_hello
+    }
+
+    /// Decrypts the next complete message in `rd`, if there is one.
+    fn decrypt_message(&mut self) -> io::Result<bool> {
+        if self.rd.len() < 2 {
+            return Ok(false);
+        }
+        let len = (usize::from(self.rd[0]) << 8) | usize::from(self.rd[1]);
+        if self.rd.len() < 2 + len {
+            return Ok(false);
+        }
+        let message = self.rd.split_to(2 + len);
+        let mut buf = vec![0u8; len];
+        let n = self
+            .noise
+            .read_message(&message[2..], &mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.plaintext.extend_from_slice(&buf[..n]);
+        Ok(true)
+    }
+}
+
+impl<S: Write> SecureStream<S> {
+    /// Writes as much buffered ciphertext as the socket will take.
+    fn write_ciphertext(&mut self) -> io::Result<()> {
+        while !self.wr.is_empty() {
+            let n = self.socket.write(&self.wr)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.wr.split_to(n);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read> Read for SecureStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.plaintext.is_empty() {
+                let n = cmp::min(buf.len(), self.plaintext.len());
+                buf[..n].copy_from_slice(&self.plaintext.split_to(n));
+                return Ok(n);
+            }
+            if self.decrypt_message()? {
+                continue;
+            }
+            let mut chunk = [0u8; 4096];
+            let n = self.socket.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.rd.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: Write> Write for SecureStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Don't take on more data while earlier ciphertext is still waiting for the socket
+        self.write_ciphertext()?;
+
+        let n = cmp::min(buf.len(), MAX_PLAINTEXT_LEN);
+        let mut message = vec![0u8; n + TAG_LEN];
+        let len = self
+            .noise
+            .write_message(&buf[..n], &mut message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.wr.reserve(2 + len);
+        self.wr.put_u16_be(len as u16);
+        self.wr.put_slice(&message[..len]);
+
+        // The plaintext has been accepted either way; `flush` picks up anything left over
+        match self.write_ciphertext() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_ciphertext()?;
+        self.socket.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for SecureStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for SecureStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_flush());
+        self.socket.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{self, Task};
+    use identity::SIGNATURE_LEN;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::runtime::current_thread;
+
+    /// Bytes going one way through a `Pipe`.
+    #[derive(Default)]
+    struct Channel {
+        data: VecDeque<u8>,
+        /// The task waiting for data, if any
+        reader: Option<Task>,
+    }
+
+    /// One end of an in-memory connection.
+    struct Pipe {
+        incoming: Arc<Mutex<Channel>>,
+        outgoing: Arc<Mutex<Channel>>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let a = Arc::new(Mutex::new(Channel::default()));
+        let b = Arc::new(Mutex::new(Channel::default()));
+        (
+            Pipe {
+                incoming: Arc::clone(&a),
+                outgoing: Arc::clone(&b),
+            },
+            Pipe {
+                incoming: b,
+                outgoing: a,
+            },
+        )
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut channel = self.incoming.lock().unwrap();
+            if channel.data.is_empty() {
+                channel.reader = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = cmp::min(buf.len(), channel.data.len());
+            for (byte, data) in buf.iter_mut().zip(channel.data.drain(..n)) {
+                *byte = data;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut channel = self.outgoing.lock().unwrap();
+            channel.data.extend(buf);
+            if let Some(reader) = channel.reader.take() {
+                reader.notify();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe {}
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    struct Side {
+        keys: Keys,
+        identity: Identity,
+    }
+
+    impl Side {
+        fn new() -> Self {
+            Side {
+                keys: Keys::generate().unwrap(),
+                identity: Identity::generate(),
+            }
+        }
+
+        fn hello(&self, nickname: &str) -> Hello {
+            Hello::new(&self.keys, &self.identity, nickname.to_owned(), None)
+        }
+    }
+
+    /// Shakes hands between `alice`, who initiates, and `bob`.
+    fn handshake(
+        alice: &Side,
+        bob: &Side,
+    ) -> Result<(SecureStream<Pipe>, SecureStream<Pipe>), Error> {
+        let (a, b) = pipe();
+        let initiator = initiate(a, &alice.keys, &alice.hello("alice"));
+        let responder = respond(b, &bob.keys, &bob.hello("bob"));
+        current_thread::block_on_all(initiator.join(responder))
+    }
+
+    #[test]
+    fn each_end_learns_who_the_other_is() {
+        let (alice, bob) = (Side::new(), Side::new());
+        let (to_bob, to_alice) = handshake(&alice, &bob).unwrap();
+
+        assert_eq!(to_bob.remote_identity(), bob.identity.public_key());
+        assert_eq!(to_bob.remote_fingerprint(), bob.keys.fingerprint());
+        assert_eq!(to_bob.remote_hello().nickname, "bob");
+        assert_eq!(to_alice.remote_identity(), alice.identity.public_key());
+        assert_eq!(to_alice.remote_fingerprint(), alice.keys.fingerprint());
+        assert_eq!(to_alice.remote_hello().nickname, "alice");
+    }
+
+    #[test]
+    fn traffic_is_encrypted_both_ways() {
+        let (alice, bob) = (Side::new(), Side::new());
+        let (mut to_bob, mut to_alice) = handshake(&alice, &bob).unwrap();
+
+        to_bob.write_all(b"secret\n").unwrap();
+        to_bob.flush().unwrap();
+        let on_the_wire: Vec<u8> = to_alice.socket.incoming.lock().unwrap().data.clone().into();
+        assert!(!on_the_wire.windows(6).any(|window| window == b"secret"));
+        let mut buf = [0u8; 7];
+        to_alice.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"secret\n");
+
+        // More than fits in one Noise message
+        let big: Vec<u8> = (0..MAX_PLAINTEXT_LEN * 2 + 10).map(|i| i as u8).collect();
+        to_alice.write_all(&big).unwrap();
+        to_alice.flush().unwrap();
+        let mut received = vec![0u8; big.len()];
+        to_bob.read_exact(&mut received).unwrap();
+        assert!(received == big);
+    }
+
+    #[test]
+    fn a_hello_vouches_for_the_transport_key_it_was_made_for() {
+        let alice = Side::new();
+        let hello = alice.hello("alice");
+        assert_eq!(
+            hello.verify(alice.keys.public_key()).unwrap(),
+            alice.identity.public_key()
+        );
+
+        // Someone else's transport key, as a man in the middle would have
+        let mallory = Side::new();
+        assert!(hello.verify(mallory.keys.public_key()).is_err());
+
+        // Claiming Alice's identity without her signature
+        let mut forged = mallory.hello("alice");
+        forged.key = hello.key.clone();
+        assert!(forged.verify(mallory.keys.public_key()).is_err());
+
+        let mut garbled = alice.hello("alice");
+        garbled.signature = "00".repeat(SIGNATURE_LEN);
+        assert!(garbled.verify(alice.keys.public_key()).is_err());
+        garbled.signature = "not hex".to_owned();
+        assert!(garbled.verify(alice.keys.public_key()).is_err());
+    }
+
+    #[test]
+    fn a_handshake_with_a_forged_hello_fails() {
+        let (alice, mallory) = (Side::new(), Side::new());
+        let mut forged = mallory.hello("alice");
+        forged.key = alice.hello("alice").key;
+        let (a, b) = pipe();
+        let initiator = initiate(a, &alice.keys, &alice.hello("alice"));
+        let responder = respond(b, &mallory.keys, &forged);
+        let result = current_thread::block_on_all(initiator.join(responder));
+        match result {
+            Err(Error::InvalidIdentity) => {}
+            other => panic!("expected InvalidIdentity, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn tampered_traffic_is_rejected() {
+        let (alice, bob) = (Side::new(), Side::new());
+        let (mut to_bob, mut to_alice) = handshake(&alice, &bob).unwrap();
+
+        to_bob.write_all(b"pay bob 10").unwrap();
+        to_bob.flush().unwrap();
+        {
+            let mut channel = to_alice.socket.incoming.lock().unwrap();
+            let last = channel.data.len() - TAG_LEN - 1;
+            channel.data[last] ^= 1;
+        }
+        let mut buf = [0u8; 10];
+        let err = to_alice.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,469 @@
+use bytes::{Buf, BufMut, BytesMut};
+use dirs;
+use hex;
+use rand;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use identity::NodeId;
+
+/// How much of a file goes in each chunk.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How far ahead of the receiver's progress the sender may get, in bytes.
+pub const WINDOW: u64 = 16 * CHUNK_SIZE as u64;
+
+/// How many random bytes make a transfer id, before hex encoding.
+const ID_BYTES: usize = 16;
+
+/// The files we've accepted from each peer, by transfer id, and haven't yet received all of.
+/// Connections share it so that they can drop file data nobody asked for.
+pub type Expected = Arc<Mutex<HashSet<(NodeId, String)>>>;
+
+/// What a sender tells the receiver about a file it wants to send.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Offer {
+    /// Random and unique to the transfer; it stays the same when the transfer is resumed
+    pub id: String,
+    /// The file's name, without any directories
+    pub name: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the whole file
+    pub sha256: String,
+}
+
+impl Offer {
+    /// Whether the id is one we'd have made: it comes from the peer, and ends up in file names.
+    pub fn has_valid_id(&self) -> bool {
+        self.id.len() == 2 * ID_BYTES && self.id.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+}
+
+/// A piece of a file. Chunks travel as binary records rather than JSON lines, so the data
+/// needs no escaping.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub id: String,
+    /// Where in the file `data` goes
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Encodes the chunk as the id's length, the id, the offset, then the data.
+    pub fn to_bytes(&self) -> BytesMut {
+        assert!(
+            self.id.len() <= usize::from(u8::MAX),
+            "chunk id too long to encode"
+        );
+        let mut bytes = BytesMut::with_capacity(1 + self.id.len() + 8 + self.data.len());
+        bytes.put_u8(self.id.len() as u8);
+        bytes.put_slice(self.id.as_bytes());
+        bytes.put_u64_be(self.offset);
+        bytes.put_slice(&self.data);
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut cursor = Cursor::new(bytes);
+        if cursor.remaining() < 1 {
+            return None;
+        }
+        let id_len = cursor.get_u8() as usize;
+        if cursor.remaining() < id_len + 8 {
+            return None;
+        }
+        let start = cursor.position() as usize;
+        let id = String::from_utf8(bytes[start..start + id_len].to_vec()).ok()?;
+        cursor.advance(id_len);
+        let offset = cursor.get_u64_be();
+        let data = bytes[cursor.position() as usize..].to_vec();
+        Some(Chunk { id, offset, data })
+    }
+}
+
+/// Everything a peer can say about a file transfer.
+#[derive(Clone, Debug)]
+pub enum Signal {
+    Offer(Offer),
+    /// The receiver wants the file, starting from `offset`.
+    Accept {
+        id: String,
+        offset: u64,
+    },
+    Reject {
+        id: String,
+    },
+    /// The receiver has written everything up to `received`.
+    Progress {
+        id: String,
+        received: u64,
+    },
+    Chunk(Chunk),
+    /// The receiver has the whole file, and it did or didn't match the hash.
+    Done {
+        id: String,
+        ok: bool,
+    },
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Where received files go unless the caller says otherwise: the downloads directory, or the
+/// current directory if there isn't one.
+pub fn default_download_dir() -> PathBuf {
+    dirs::download_dir()
+        .or_else(|| env::current_dir().ok())
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// A file we're sending.
+#[derive(Debug)]
+pub struct Outgoing {
+    pub offer: Offer,
+    pub to: NodeId,
+    path: PathBuf,
+    /// How much the receiver has confirmed it's written
+    pub acked: u64,
+    /// How much we've handed to the connection
+    sent: u64,
+    /// Whether the receiver has accepted, so chunks may flow
+    accepted: bool,
+}
+
+impl Outgoing {
+    pub fn new(path: &Path, to: NodeId) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?
+            .to_owned();
+        let offer = Offer {
+            id: hex::encode(rand::random::<[u8; ID_BYTES]>()),
+            name,
+            size: fs::metadata(path)?.len(),
+            sha256: hash_file(path)?,
+        };
+        Ok(Outgoing {
+            offer,
+            to,
+            path: path.to_owned(),
+            acked: 0,
+            sent: 0,
+            accepted: false,
+        })
+    }
+
+    /// Starts, or restarts after a reconnection, sending from `offset`.
+    pub fn accepted(&mut self, offset: u64) {
+        self.accepted = true;
+        self.acked = offset.min(self.offer.size);
+        self.sent = self.acked;
+    }
+
+    /// Waits for the receiver to accept again, e.g. because the connection dropped.
+    pub fn pause(&mut self) {
+        self.accepted = false;
+    }
+
+    pub fn progress(&mut self, received: u64) {
+        self.acked = self.acked.max(received.min(self.offer.size));
+    }
+
+    /// Reads the chunks that fit in the window from where we left off.
+    pub fn next_chunks(&mut self) -> io::Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        if !self.accepted {
+            return Ok(chunks);
+        }
+        let end = (self.acked + WINDOW).min(self.offer.size);
+        if self.sent >= end {
+            return Ok(chunks);
+        }
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.sent))?;
+        while self.sent < end {
+            let len = ((end - self.sent) as usize).min(CHUNK_SIZE);
+            let mut data = vec![0u8; len];
+            file.read_exact(&mut data)?;
+            chunks.push(Chunk {
+                id: self.offer.id.clone(),
+                offset: self.sent,
+                data,
+            });
+            self.sent += len as u64;
+        }
+        Ok(chunks)
+    }
+}
+
+/// A file we're receiving. It's written to a `.part` file next to its destination, which is
+/// kept if the transfer is interrupted so that it can carry on where it left off.
+#[derive(Debug)]
+pub struct Incoming {
+    pub offer: Offer,
+    pub from: NodeId,
+    path: PathBuf,
+    part_path: PathBuf,
+    /// How much of the file we've written
+    pub received: u64,
+}
+
+impl Incoming {
+    /// Prepares to receive `offer` into `dir`, picking up any part of it left from before.
+    pub fn new(offer: Offer, from: NodeId, dir: &Path) -> io::Result<Self> {
+        if !offer.has_valid_id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bad transfer id",
+            ));
+        }
+        // The name comes from the peer; don't let it point anywhere but `dir`
+        let name = Path::new(&offer.name)
+            .file_name()
+            .map(|name| name.to_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad file name"))?;
+        let path = dir.join(&name);
+        let mut part_name = name;
+        part_name.push(format!(".{}.part", &offer.id[..8]));
+        let part_path = dir.join(part_name);
+        let received = match fs::metadata(&part_path) {
+            Ok(metadata) => metadata.len().min(offer.size),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(Incoming {
+            offer,
+            from,
+            path,
+            part_path,
+            received,
+        })
+    }
+
+    /// Writes a chunk. Chunks must arrive in order; anything else is ignored, and returns
+    /// `false`.
+    pub fn write(&mut self, chunk: &Chunk) -> io::Result<bool> {
+        if chunk.offset != self.received
+            || self.received + chunk.data.len() as u64 > self.offer.size
+        {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.part_path)?;
+        // A previous attempt may have written more than it got to confirm
+        file.set_len(self.received)?;
+        file.write_all(&chunk.data)?;
+        self.received += chunk.data.len() as u64;
+        Ok(true)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.offer.size
+    }
+
+    /// Checks the complete file against the offered hash, and moves it into place if it
+    /// matches, under another name if there's already a file by its name. Returns where it
+    /// ended up, or `None` if it didn't match and was discarded.
+    pub fn finish(&self) -> io::Result<Option<PathBuf>> {
+        if self.offer.size == 0 {
+            File::create(&self.part_path)?;
+        }
+        if hash_file(&self.part_path)? != self.offer.sha256 {
+            fs::remove_file(&self.part_path)?;
+            return Ok(None);
+        }
+        let path = unused_path(&self.path);
+        fs::rename(&self.part_path, &path)?;
+        Ok(Some(path))
+    }
+}
+
+/// `path` if there's nothing there, or else the first of `name (1).ext`, `name (2).ext` and so
+/// on that's free.
+fn unused_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_owned();
+    }
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("localchat-test-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn offer(name: &str) -> Offer {
+        Offer {
+            id: hex::encode([0xabu8; ID_BYTES]),
+            name: name.to_owned(),
+            size: 0,
+            sha256: hex::encode(Sha256::digest(b"")),
+        }
+    }
+
+    fn sender() -> NodeId {
+        NodeId::from("0123456789abcdef0123456789abcdef")
+    }
+
+    #[test]
+    fn only_ids_we_would_have_made_are_valid() {
+        assert!(offer("a").has_valid_id());
+        for id in &[
+            "",
+            "abab",
+            "../../../../../../../../../../etc",
+            "abababababababababababababababa/",
+            "abababababababababababababababababab",
+            "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz",
+        ] {
+            let mut offer = offer("a");
+            offer.id = id.to_string();
+            assert!(!offer.has_valid_id(), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn an_offer_with_a_bad_id_is_refused() {
+        let dir = temp_dir();
+        let mut offer = offer("notes.txt");
+        offer.id = "../../notes".to_owned();
+        let err = Incoming::new(offer, sender(), &dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_can_only_be_received_into_the_download_dir() {
+        let dir = temp_dir();
+        for (name, expected) in &[
+            ("notes.txt", "notes.txt"),
+            ("../notes.txt", "notes.txt"),
+            ("../../../etc/passwd", "passwd"),
+            ("/etc/passwd", "passwd"),
+            ("sub/dir/notes.txt", "notes.txt"),
+        ] {
+            let incoming = Incoming::new(offer(name), sender(), &dir).unwrap();
+            assert_eq!(incoming.path, dir.join(expected), "{:?}", name);
+            assert_eq!(incoming.part_path.parent(), Some(dir.as_path()));
+        }
+        for name in &["", "..", "../..", "/", "notes/.."] {
+            let err = Incoming::new(offer(name), sender(), &dir).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unused_path_numbers_names_that_are_taken() {
+        let dir = temp_dir();
+        let path = dir.join("notes.txt");
+        assert_eq!(unused_path(&path), path);
+        fs::write(&path, "").unwrap();
+        assert_eq!(unused_path(&path), dir.join("notes (1).txt"));
+        fs::write(dir.join("notes (1).txt"), "").unwrap();
+        assert_eq!(unused_path(&path), dir.join("notes (2).txt"));
+
+        let bare = dir.join("README");
+        fs::write(&bare, "").unwrap();
+        assert_eq!(unused_path(&bare), dir.join("README (1)"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_file_goes_across_in_chunks_and_is_checked() {
+        let from = temp_dir();
+        let to = temp_dir();
+        let path = from.join("data.bin");
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        fs::write(to.join("data.bin"), "already here").unwrap();
+
+        let mut outgoing = Outgoing::new(&path, sender()).unwrap();
+        assert!(outgoing.offer.has_valid_id());
+        assert!(outgoing.next_chunks().unwrap().is_empty());
+        let mut incoming = Incoming::new(outgoing.offer.clone(), sender(), &to).unwrap();
+        outgoing.accepted(incoming.received);
+        let chunks = outgoing.next_chunks().unwrap();
+        assert_eq!(chunks.len(), 3);
+        for chunk in &chunks {
+            let chunk = Chunk::parse(&chunk.to_bytes()).unwrap();
+            assert!(incoming.write(&chunk).unwrap());
+        }
+        // Repeats are ignored
+        assert!(!incoming.write(&chunks[0]).unwrap());
+        assert!(incoming.is_complete());
+
+        let received = incoming.finish().unwrap().unwrap();
+        assert_eq!(received, to.join("data (1).bin"));
+        assert_eq!(fs::read(&received).unwrap(), contents);
+        fs::remove_dir_all(&from).unwrap();
+        fs::remove_dir_all(&to).unwrap();
+    }
+
+    #[test]
+    fn a_file_that_does_not_match_its_hash_is_discarded() {
+        let dir = temp_dir();
+        let mut offer = offer("data.bin");
+        offer.size = 4;
+        let mut incoming = Incoming::new(offer.clone(), sender(), &dir).unwrap();
+        let chunk = Chunk {
+            id: offer.id,
+            offset: 0,
+            data: b"evil".to_vec(),
+        };
+        assert!(incoming.write(&chunk).unwrap());
+        assert_eq!(incoming.finish().unwrap(), None);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_chunks_do_not_parse() {
+        let chunk = Chunk {
+            id: "abc".to_owned(),
+            offset: 42,
+            data: b"data".to_vec(),
+        };
+        let bytes = chunk.to_bytes();
+        let parsed = Chunk::parse(&bytes).unwrap();
+        assert_eq!((parsed.id.as_str(), parsed.offset), ("abc", 42));
+        assert_eq!(parsed.data, b"data");
+        assert!(Chunk::parse(&bytes[..1 + 3 + 7]).is_none());
+        assert!(Chunk::parse(&[]).is_none());
+    }
+}
@@ -0,0 +1,595 @@
+use libc;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::mem;
+
+/// How many lines of messages to keep for scrolling back through.
+const SCROLLBACK: usize = 5000;
+
+/// How wide the peer list is, border included.
+const SIDEBAR_WIDTH: usize = 28;
+
+/// Puts the terminal in raw mode on an alternate screen, and puts it back when dropped.
+pub struct Terminal {
+    original: libc::termios,
+}
+
+impl Terminal {
+    pub fn enter() -> io::Result<Self> {
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = termios;
+        // We handle every key ourselves, Ctrl-C included
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        termios.c_iflag &= !(libc::IXON | libc::ICRNL);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x1b[?1049h\x1b[H\x1b[2J")?;
+        stdout.flush()?;
+        Ok(Terminal { original })
+    }
+
+    /// The terminal's width and height, in characters.
+    pub fn size() -> (usize, usize) {
+        let mut size: libc::winsize = unsafe { mem::zeroed() };
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        if ok && size.ws_col > 0 && size.ws_row > 0 {
+            (size.ws_col as usize, size.ws_row as usize)
+        } else {
+            (80, 24)
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x1b[?1049l");
+        let _ = stdout.flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// A key press, decoded from what the terminal sends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Key {
+    Char(char),
+    /// A control character, e.g. `Ctrl('a')` for Ctrl-A
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Escape,
+}
+
+/// Turns the bytes read from a raw terminal into keys. Escape sequences and UTF-8 characters
+/// may be split across reads, so what can't be decoded yet is kept for next time.
+#[derive(Debug, Default)]
+pub struct KeyParser {
+    pending: Vec<u8>,
+}
+
+impl KeyParser {
+    pub fn new() -> Self {
+        KeyParser::default()
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(bytes);
+        let mut keys = Vec::new();
+        let mut start = 0;
+        while start < self.pending.len() {
+            match decode(&self.pending[start..]) {
+                Some((key, len)) => {
+                    keys.extend(key);
+                    start += len;
+                }
+                None => break,
+            }
+        }
+        self.pending.drain(..start);
+        keys
+    }
+}
+
+/// Decodes the key at the start of `bytes`, returning it (or nothing, for sequences we don't
+/// know) and how many bytes it took up. Returns `None` if more bytes are needed.
+fn decode(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let key = match bytes[0] {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => return decode_escape(bytes),
+        byte @ 0x01..=0x1a => Key::Ctrl((b'a' + byte - 1) as char),
+        byte if byte < 0x20 => return Some((None, 1)),
+        byte => {
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            if bytes.len() < len {
+                return None;
+            }
+            return match std::str::from_utf8(&bytes[..len]) {
+                // Including the C1 controls, which some terminals act on
+                Ok(s) => Some((
+                    s.chars().next().filter(|c| !c.is_control()).map(Key::Char),
+                    len,
+                )),
+                Err(_) => Some((None, 1)),
+            };
+        }
+    };
+    Some((Some(key), 1))
+}
+
+fn decode_escape(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    if bytes.len() == 1 {
+        // A lone escape, or the start of a sequence; there's no telling yet, but a lone escape
+        // is all the user will have pressed if nothing follows in the same read
+        return Some((Some(Key::Escape), 1));
+    }
+    if bytes[1] != b'[' && bytes[1] != b'O' {
+        return Some((Some(Key::Escape), 1));
+    }
+    // The sequence ends at the first byte in 0x40..=0x7e after the introducer
+    let end = bytes[2..]
+        .iter()
+        .position(|byte| (0x40..=0x7e).contains(byte))?;
+    let sequence = &bytes[2..2 + end + 1];
+    let key = match sequence {
+        b"A" => Some(Key::Up),
+        b"B" => Some(Key::Down),
+        b"C" => Some(Key::Right),
+        b"D" => Some(Key::Left),
+        b"H" | b"1~" | b"7~" => Some(Key::Home),
+        b"F" | b"4~" | b"8~" => Some(Key::End),
+        b"3~" => Some(Key::Delete),
+        b"5~" => Some(Key::PageUp),
+        b"6~" => Some(Key::PageDown),
+        _ => None,
+    };
+    Some((key, 2 + end + 1))
+}
+
+/// A single line of input with cursor movement and a history of what was entered before.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    /// Which history entry is being shown, while going through it
+    browsing: Option<usize>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor::default()
+    }
+
+    pub fn text(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.cursor == self.buffer.len()
+    }
+
+    /// Replaces what's been typed, leaving the cursor at the end.
+    pub fn set_text(&mut self, text: &str) {
+        self.buffer = text.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    /// Applies a key. Returns the line if the key entered it.
+    pub fn handle_key(&mut self, key: Key) -> Option<String> {
+        match key {
+            Key::Char(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => {
+                let line = self.text();
+                if !line.is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                self.buffer.clear();
+                self.cursor = 0;
+                self.browsing = None;
+                return Some(line);
+            }
+            Key::Backspace | Key::Ctrl('h') if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buffer.remove(self.cursor);
+            }
+            Key::Delete | Key::Ctrl('d') if self.cursor < self.buffer.len() => {
+                self.buffer.remove(self.cursor);
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.buffer.len(),
+            Key::Ctrl('u') => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Ctrl('k') => self.buffer.truncate(self.cursor),
+            Key::Ctrl('w') => {
+                let mut start = self.cursor;
+                while start > 0 && self.buffer[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.buffer[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.buffer.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Up => {
+                let index = match self.browsing {
+                    Some(index) => index.saturating_sub(1),
+                    None if self.history.is_empty() => return None,
+                    None => self.history.len() - 1,
+                };
+                self.browsing = Some(index);
+                let entry = self.history[index].clone();
+                self.set_text(&entry);
+            }
+            Key::Down => match self.browsing {
+                Some(index) if index + 1 < self.history.len() => {
+                    self.browsing = Some(index + 1);
+                    let entry = self.history[index + 1].clone();
+                    self.set_text(&entry);
+                }
+                Some(_) => {
+                    self.browsing = None;
+                    self.set_text("");
+                }
+                None => {}
+            },
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Breaks `text` into lines no wider than `width` characters, at its own line breaks and
+/// wherever else it needs to.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for piece in chars.chunks(width) {
+            lines.push(piece.iter().collect());
+        }
+    }
+    lines
+}
+
+/// Replaces control characters with a visible stand-in. Much of what's shown comes from peers,
+/// and written as is, escape sequences in it could redraw the screen or retitle the window.
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\t' => ' ',
+            c if c.is_control() => '\u{fffd}',
+            c => c,
+        })
+        .collect()
+}
+
+/// Pads or cuts `text` to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - len));
+    fitted
+}
+
+/// Everything on screen: messages on the left, peers on the right, a status bar, and the input
+/// line at the bottom.
+#[derive(Debug, Default)]
+pub struct Screen {
+    messages: VecDeque<String>,
+    /// How many lines up from the bottom the message pane is scrolled
+    scroll: usize,
+    sidebar: Vec<String>,
+    status: String,
+    pub editor: LineEditor,
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen::default()
+    }
+
+    pub fn push_message(&mut self, text: String) {
+        // Messages are wrapped at their own line breaks, so those can stay
+        let lines: Vec<String> = text.lines().map(sanitize).collect();
+        self.messages.push_back(lines.join("\n"));
+        if self.messages.len() > SCROLLBACK {
+            self.messages.pop_front();
+        }
+        // Keep what the user is reading in place while scrolled back
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    pub fn set_sidebar(&mut self, lines: Vec<String>) {
+        self.sidebar = lines.iter().map(|line| sanitize(line)).collect();
+    }
+
+    pub fn set_status(&mut self, status: String) {
+        self.status = sanitize(&status);
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.messages.len());
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    /// Draws the whole screen at `width` by `height` characters.
+    pub fn render(&self, width: usize, height: usize) -> String {
+        let pane_height = height.saturating_sub(2);
+        let sidebar_width = if width > SIDEBAR_WIDTH * 2 {
+            SIDEBAR_WIDTH
+        } else {
+            0
+        };
+        let pane_width = width - sidebar_width;
+
+        // Wrap from the bottom up until the pane and the scrollback are full
+        let mut lines = Vec::new();
+        for message in self.messages.iter().rev() {
+            let wrapped = wrap(message, pane_width);
+            lines.extend(wrapped.into_iter().rev());
+            if lines.len() >= pane_height + self.scroll {
+                break;
+            }
+        }
+        let skip = self.scroll.min(lines.len().saturating_sub(pane_height));
+        let mut visible: Vec<&String> = lines.iter().skip(skip).take(pane_height).collect();
+        visible.reverse();
+
+        let mut out = String::from("\x1b[?25l\x1b[H");
+        for row in 0..pane_height {
+            // Messages sit at the bottom of the pane, like a terminal
+            let padding = pane_height - visible.len();
+            let message = if row >= padding {
+                visible[row - padding].as_str()
+            } else {
+                ""
+            };
+            out.push_str(&fit(message, pane_width));
+            if sidebar_width > 0 {
+                out.push('\u{2502}');
+                let peer = self.sidebar.get(row).map(String::as_str).unwrap_or("");
+                out.push_str(&fit(peer, sidebar_width - 1));
+            }
+            out.push_str("\r\n");
+        }
+
+        let status = if self.scroll > 0 {
+            format!("{} [scrolled back {} lines]", self.status, self.scroll)
+        } else {
+            self.status.clone()
+        };
+        out.push_str("\x1b[7m");
+        out.push_str(&fit(&status, width));
+        out.push_str("\x1b[0m\r\n");
+
+        // Scroll the input sideways to keep the cursor in view
+        let prompt = "> ";
+        let room = width.saturating_sub(prompt.len() + 1).max(1);
+        let text: Vec<char> = self.editor.text().chars().collect();
+        let start = self.editor.cursor().saturating_sub(room);
+        let shown: String = text.iter().skip(start).take(room).collect();
+        out.push_str(prompt);
+        out.push_str(&fit(&shown, width.saturating_sub(prompt.len())));
+        let column = prompt.len() + self.editor.cursor() - start + 1;
+        out.push_str(&format!("\x1b[{};{}H\x1b[?25h", height, column));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_text(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            editor.handle_key(Key::Char(c));
+        }
+    }
+
+    #[test]
+    fn keys_are_decoded() {
+        let mut parser = KeyParser::new();
+        assert_eq!(
+            parser.parse(b"a\x01\r\t\x7f\x1b[A\x1b[3~\x1bOH"),
+            vec![
+                Key::Char('a'),
+                Key::Ctrl('a'),
+                Key::Enter,
+                Key::Tab,
+                Key::Backspace,
+                Key::Up,
+                Key::Delete,
+                Key::Home,
+            ]
+        );
+    }
+
+    #[test]
+    fn escape_sequences_split_across_reads_are_put_back_together() {
+        let mut parser = KeyParser::new();
+        assert_eq!(parser.parse(b"x\x1b["), vec![Key::Char('x')]);
+        assert_eq!(parser.parse(b"5"), vec![]);
+        assert_eq!(parser.parse(b"~y"), vec![Key::PageUp, Key::Char('y')]);
+    }
+
+    #[test]
+    fn unknown_escape_sequences_are_skipped() {
+        let mut parser = KeyParser::new();
+        assert_eq!(parser.parse(b"\x1b[1;5Pz"), vec![Key::Char('z')]);
+    }
+
+    #[test]
+    fn utf8_split_across_reads_is_put_back_together() {
+        let mut parser = KeyParser::new();
+        let bytes = "é€".as_bytes();
+        assert_eq!(parser.parse(&bytes[..1]), vec![]);
+        assert_eq!(parser.parse(&bytes[1..3]), vec![Key::Char('é')]);
+        assert_eq!(parser.parse(&bytes[3..4]), vec![]);
+        assert_eq!(parser.parse(&bytes[4..]), vec![Key::Char('€')]);
+    }
+
+    #[test]
+    fn control_characters_are_not_typed() {
+        let mut parser = KeyParser::new();
+        // U+009B is a one-character CSI on some terminals
+        assert_eq!(parser.parse("\u{9b}a".as_bytes()), vec![Key::Char('a')]);
+    }
+
+    #[test]
+    fn editing_moves_the_cursor() {
+        let mut editor = LineEditor::new();
+        type_text(&mut editor, "helo");
+        editor.handle_key(Key::Left);
+        editor.handle_key(Key::Char('l'));
+        assert_eq!(editor.text(), "hello");
+        assert_eq!(editor.cursor(), 4);
+        editor.handle_key(Key::Ctrl('a'));
+        editor.handle_key(Key::Delete);
+        assert_eq!(editor.text(), "ello");
+        editor.handle_key(Key::End);
+        editor.handle_key(Key::Backspace);
+        assert_eq!(editor.text(), "ell");
+        assert!(editor.at_end());
+    }
+
+    #[test]
+    fn ctrl_w_deletes_the_word_before_the_cursor() {
+        let mut editor = LineEditor::new();
+        type_text(&mut editor, "one two  three  ");
+        editor.handle_key(Key::Ctrl('w'));
+        assert_eq!(editor.text(), "one two  ");
+        editor.handle_key(Key::Ctrl('w'));
+        assert_eq!(editor.text(), "one ");
+        editor.handle_key(Key::Left);
+        editor.handle_key(Key::Ctrl('w'));
+        assert_eq!(editor.text(), " ");
+        assert_eq!(editor.cursor(), 0);
+    }
+
+    #[test]
+    fn history_is_browsed_with_up_and_down() {
+        let mut editor = LineEditor::new();
+        for line in &["first", "second", "second"] {
+            type_text(&mut editor, line);
+            assert_eq!(editor.handle_key(Key::Enter), Some(line.to_string()));
+        }
+        type_text(&mut editor, "draft");
+        editor.handle_key(Key::Up);
+        assert_eq!(editor.text(), "second");
+        editor.handle_key(Key::Up);
+        assert_eq!(editor.text(), "first");
+        // There's nothing before the first entry
+        editor.handle_key(Key::Up);
+        assert_eq!(editor.text(), "first");
+        editor.handle_key(Key::Down);
+        assert_eq!(editor.text(), "second");
+        editor.handle_key(Key::Down);
+        assert_eq!(editor.text(), "");
+        assert_eq!(editor.handle_key(Key::Enter), Some(String::new()));
+    }
+
+    #[test]
+    fn wrap_breaks_long_lines_and_keeps_empty_ones() {
+        assert_eq!(wrap("abcdefg", 3), vec!["abc", "def", "g"]);
+        assert_eq!(wrap("ab\n\ncd", 5), vec!["ab", "", "cd"]);
+        assert_eq!(wrap("é€ab", 2), vec!["é€", "ab"]);
+        assert_eq!(wrap("ab", 0), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn fit_pads_and_cuts() {
+        assert_eq!(fit("ab", 4), "ab  ");
+        assert_eq!(fit("abcdef", 4), "abcd");
+        assert_eq!(fit("", 0), "");
+    }
+
+    #[test]
+    fn render_fills_the_screen_with_the_latest_messages() {
+        let mut screen = Screen::new();
+        for n in 0..10 {
+            screen.push_message(format!("message {}", n));
+        }
+        screen.set_status("status".to_owned());
+        type_text(&mut screen.editor, "hi");
+        let out = screen.render(20, 5);
+        let rows: Vec<&str> = out.split("\r\n").collect();
+        assert_eq!(rows.len(), 5);
+        assert!(rows[0].ends_with("message 7           "));
+        assert!(rows[2].starts_with("message 9"));
+        assert!(rows[3].contains("status"));
+        assert!(rows[4].starts_with("> hi"));
+        // The cursor ends up after what's been typed
+        assert!(rows[4].ends_with("\x1b[5;5H\x1b[?25h"));
+    }
+
+    #[test]
+    fn render_shows_no_escape_sequences_from_messages() {
+        let mut screen = Screen::new();
+        screen.push_message("evil\x1b]0;title\x07\x1b[2J\rline\nnext".to_owned());
+        screen.set_sidebar(vec!["peer\x1b[31m".to_owned()]);
+        screen.set_status("bad\nstatus\x1b[H".to_owned());
+        let out = screen.render(80, 6);
+        let ours = Screen::new().render(80, 6);
+        assert_eq!(out.matches('\x1b').count(), ours.matches('\x1b').count());
+        assert!(!out.contains('\x07'));
+        assert!(out.contains("evil\u{fffd}]0;title"));
+        assert!(out.contains("next"));
+    }
+
+    #[test]
+    fn render_survives_tiny_terminals() {
+        let mut screen = Screen::new();
+        screen.push_message("hello".to_owned());
+        type_text(&mut screen.editor, "typed");
+        for &(width, height) in &[(1, 1), (0, 0), (2, 3), (57, 2)] {
+            screen.render(width, height);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+extern crate localchat;
+
+use std::time::{Duration, Instant};
+
+use localchat::identity::Identity;
+use localchat::message::Message;
+use localchat::ordering::{LamportClock, ReorderBuffer};
+
+const HOLD_MS: u64 = 500;
+
+/// A node as far as ordering is concerned: it stamps what it sends, and shows what it receives
+/// once its reorder buffer releases it.
+struct Node {
+    identity: Identity,
+    clock: LamportClock,
+    buffer: ReorderBuffer,
+    shown: Vec<Message>,
+}
+
+impl Node {
+    fn new() -> Self {
+        Node {
+            identity: Identity::generate(),
+            clock: LamportClock::new(),
+            buffer: ReorderBuffer::new(Duration::from_millis(HOLD_MS)),
+            shown: Vec::new(),
+        }
+    }
+}
+
+/// Delivers messages between in-process nodes with whatever delay each test asks for, so that
+/// deliveries can be interleaved differently at each node.
+struct Harness {
+    start: Instant,
+    now_ms: u64,
+    nodes: Vec<Node>,
+    /// Recipient, when it gets the message, and the message
+    in_flight: Vec<(usize, u64, Message)>,
+}
+
+impl Harness {
+    fn new(nodes: usize) -> Self {
+        Harness {
+            start: Instant::now(),
+            now_ms: 0,
+            nodes: (0..nodes).map(|_| Node::new()).collect(),
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn instant(&self, ms: u64) -> Instant {
+        self.start + Duration::from_millis(ms)
+    }
+
+    /// Has node `from` send `body` now, reaching each `(recipient, delay in ms)`.
+    fn send(&mut self, from: usize, body: &str, deliveries: &[(usize, u64)]) {
+        let node = &mut self.nodes[from];
+        let clock = node.clock.tick();
+        let message = Message::new(&node.identity, "node".to_owned(), clock, body.to_owned());
+        for &(recipient, delay) in deliveries {
+            self.in_flight
+                .push((recipient, self.now_ms + delay, message.clone()));
+        }
+    }
+
+    /// Advances time to `ms`, delivering messages and releasing them from reorder buffers as
+    /// it goes.
+    fn run_until(&mut self, ms: u64) {
+        while self.now_ms < ms {
+            self.now_ms += 10;
+            let now = self.instant(self.now_ms);
+            let now_ms = self.now_ms;
+            let (due, later) = self
+                .in_flight
+                .drain(..)
+                .partition(|&(_, at, _)| at <= now_ms);
+            self.in_flight = later;
+            for (recipient, _, message) in due {
+                let node = &mut self.nodes[recipient];
+                assert!(message.verify());
+                node.clock.observe(message.clock);
+                node.buffer.push(message, now);
+            }
+            for node in &mut self.nodes {
+                let ready = node.buffer.pop_ready(now);
+                node.shown.extend(ready);
+            }
+        }
+    }
+
+    fn shown(&self, node: usize) -> Vec<&str> {
+        self.nodes[node]
+            .shown
+            .iter()
+            .map(|message| message.body.as_str())
+            .collect()
+    }
+}
+
+#[test]
+fn reply_overtaking_its_original_is_shown_after_it() {
+    let mut harness = Harness::new(3);
+    // 0's message crawls to 2, but 1 gets it quickly and replies
+    harness.send(0, "question", &[(1, 10), (2, 700)]);
+    harness.run_until(HOLD_MS + 20);
+    harness.send(1, "answer", &[(0, 10), (2, 10)]);
+    harness.run_until(4 * HOLD_MS);
+
+    assert_eq!(harness.shown(1), vec!["question"]);
+    assert_eq!(harness.shown(0), vec!["answer"]);
+    assert_eq!(harness.shown(2), vec!["question", "answer"]);
+}
+
+#[test]
+fn reply_arriving_before_original_within_hold_is_reordered() {
+    let mut harness = Harness::new(3);
+    harness.send(0, "question", &[(1, 10), (2, 200)]);
+    harness.run_until(10);
+    // 1 has seen the question, so its clock has moved past it
+    harness.send(1, "answer", &[(2, 10)]);
+    harness.run_until(4 * HOLD_MS);
+
+    assert_eq!(harness.shown(2), vec!["question", "answer"]);
+}
+
+#[test]
+fn concurrent_messages_are_shown_in_the_same_order_everywhere() {
+    let mut harness = Harness::new(4);
+    // 0 and 1 speak at once, and 2 and 3 hear them in opposite orders
+    harness.send(0, "from zero", &[(2, 10), (3, 100)]);
+    harness.send(1, "from one", &[(2, 100), (3, 10)]);
+    harness.run_until(4 * HOLD_MS);
+
+    assert_eq!(harness.shown(2).len(), 2);
+    assert_eq!(harness.shown(2), harness.shown(3));
+}
+
+#[test]
+fn message_later_than_the_hold_is_still_shown() {
+    let mut harness = Harness::new(3);
+    harness.send(0, "slow", &[(2, 3 * HOLD_MS)]);
+    harness.run_until(10);
+    harness.send(1, "fast", &[(2, 10)]);
+    harness.run_until(6 * HOLD_MS);
+
+    // "slow" sorts first, but "fast" was long gone by the time it turned up
+    assert_eq!(harness.shown(2), vec!["fast", "slow"]);
+}
+
+#[test]
+fn clock_moves_past_everything_observed() {
+    let mut clock = LamportClock::new();
+    assert_eq!(clock.tick(), 1);
+    clock.observe(41);
+    assert_eq!(clock.tick(), 42);
+    clock.observe(7);
+    assert_eq!(clock.time(), 42);
+}